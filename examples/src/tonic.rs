@@ -10,7 +10,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let project = env::args().nth(1).expect("cargo run --bin tonic -- <GCP_PROJECT_ID>");
     let channel = Channel::from_static("https://pubsub.googleapis.com").connect().await?;
-    let channel = GoogleAuthz::new(channel).await;
+    // The requests to the channel have no scheme, the channel connects with TLS instead.
+    let channel = GoogleAuthz::builder(channel).enforce_https(false).build().await;
 
     let mut client = PublisherClient::new(channel);
     let response = client
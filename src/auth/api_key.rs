@@ -1,41 +1,84 @@
 use std::{convert::TryFrom as _, fmt};
 
-use hyper::{http::uri::PathAndQuery, Request, Uri};
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    http::uri::PathAndQuery,
+    Request, Uri,
+};
+
+use crate::auth::{Error, Result};
+
+pub(crate) const X_GOOG_API_KEY: &str = "x-goog-api-key";
 
 // https://cloud.google.com/docs/authentication/api-keys
 #[derive(Clone)]
 pub(super) struct ApiKey {
-    value: String,
+    key: String,
+    // Percent-encoded `key=<value>` query pair.
+    query: String,
+    // Sends the key in the `X-Goog-Api-Key` header so that the key does not appear in URLs.
+    in_header: bool,
 }
 
 impl ApiKey {
-    pub fn new(key: impl Into<String>) -> Self {
-        Self { value: key.into() }
+    pub fn new(key: impl Into<String>, in_header: bool) -> Self {
+        let key = key.into();
+        let query = serde_urlencoded::to_string([("key", &key)]).unwrap();
+        Self { key, query, in_header }
     }
 
     #[inline]
-    pub fn add_query<B>(&self, req: Request<B>) -> Request<B> {
-        let (mut head, body) = req.into_parts();
-        let s = {
-            let mut s = head.uri.path().to_owned();
-            s.push('?');
-            if let Some(q) = head.uri.query() {
+    pub fn add_key<B>(&self, req: Request<B>) -> Result<Request<B>> {
+        if self.in_header {
+            add_header(&self.key, req)
+        } else {
+            add_query(&self.query, req)
+        }
+    }
+
+    // Adds the key in the header even if it is sent in the query otherwise, for requests that
+    // have no query, e.g. gRPC calls.
+    pub fn add_key_header(&self, headers: &mut HeaderMap) -> Result<()> {
+        headers.insert(HeaderName::from_static(X_GOOG_API_KEY), header_value(&self.key)?);
+        Ok(())
+    }
+}
+
+fn add_query<B>(query: &str, req: Request<B>) -> Result<Request<B>> {
+    let (mut head, body) = req.into_parts();
+    let s = {
+        let mut s = head.uri.path().to_owned();
+        s.push('?');
+        match head.uri.query() {
+            Some(q) if !q.is_empty() => {
                 s.push_str(q);
                 if !q.ends_with('&') {
                     s.push('&')
                 }
             }
-            s.push_str("key=");
-            s.push_str(&self.value);
-            s
-        };
+            _ => {}
+        }
+        s.push_str(query);
+        s
+    };
 
-        let mut parts = head.uri.into_parts();
-        parts.path_and_query = Some(PathAndQuery::try_from(s).unwrap());
+    let mut parts = head.uri.into_parts();
+    parts.path_and_query =
+        Some(PathAndQuery::try_from(s).map_err(|err| Error::ApiKeyUri(err.into()))?);
 
-        head.uri = Uri::from_parts(parts).unwrap();
-        Request::from_parts(head, body)
-    }
+    head.uri = Uri::from_parts(parts).map_err(|err| Error::ApiKeyUri(err.into()))?;
+    Ok(Request::from_parts(head, body))
+}
+
+fn add_header<B>(key: &str, mut req: Request<B>) -> Result<Request<B>> {
+    req.headers_mut().insert(HeaderName::from_static(X_GOOG_API_KEY), header_value(key)?);
+    Ok(req)
+}
+
+fn header_value(key: &str) -> Result<HeaderValue> {
+    let mut value = HeaderValue::from_str(key).map_err(Error::ApiKeyHeader)?;
+    value.set_sensitive(true);
+    Ok(value)
 }
 
 impl fmt::Debug for ApiKey {
@@ -43,3 +86,51 @@ impl fmt::Debug for ApiKey {
         f.debug_struct("ApiKey").finish()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_query() {
+        let key = ApiKey::new("api-key", false);
+        let uri =
+            |uri: &str| key.add_key(Request::get(uri).body(()).unwrap()).unwrap().uri().to_string();
+
+        assert_eq!(uri("https://example.com/path"), "https://example.com/path?key=api-key");
+        assert_eq!(
+            uri("https://example.com/a%20b?x=1"),
+            "https://example.com/a%20b?x=1&key=api-key"
+        );
+        assert_eq!(uri("https://example.com/path?"), "https://example.com/path?key=api-key");
+        assert_eq!(
+            uri("https://example.com/path?x=1&"),
+            "https://example.com/path?x=1&key=api-key"
+        );
+        assert_eq!(
+            uri("https://example.com/path?b=2&a=1"),
+            "https://example.com/path?b=2&a=1&key=api-key"
+        );
+
+        let key = ApiKey::new("a+b&c=d", false);
+        let req = key.add_key(Request::get("https://example.com/").body(()).unwrap()).unwrap();
+        assert_eq!(req.uri().query(), Some("key=a%2Bb%26c%3Dd"));
+    }
+
+    #[test]
+    fn test_add_header() {
+        let key = ApiKey::new("api-key", true);
+        let req = key.add_key(Request::get("https://example.com/path").body(()).unwrap()).unwrap();
+        assert_eq!(req.uri(), "https://example.com/path");
+        assert_eq!(req.headers()[X_GOOG_API_KEY], "api-key");
+        assert!(req.headers()[X_GOOG_API_KEY].is_sensitive());
+
+        let mut headers = HeaderMap::new();
+        ApiKey::new("a+b&c=d", false).add_key_header(&mut headers).unwrap();
+        assert_eq!(headers[X_GOOG_API_KEY], "a+b&c=d");
+
+        let key = ApiKey::new("invalid\n", true);
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        assert!(matches!(key.add_key(req), Err(Error::ApiKeyHeader(_))));
+    }
+}
@@ -1,3 +1,7 @@
+use std::sync::Arc;
+
+use hyper::StatusCode;
+
 /// Represents errors that can occur during fetching token.
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -5,16 +9,169 @@ pub enum Error {
     Gcemeta(#[from] gcemeta::Error),
     #[error("http client error: {0}")]
     Http(#[from] hyper::Error),
+    #[cfg(feature = "reqwest")]
+    #[error("reqwest client error: {0}")]
+    Reqwest(#[from] reqwest::Error),
     #[error("response status code error: {0:?}")]
     StatusCode((hyper::http::response::Parts, hyper::Body)),
+    #[error("invalid grant, the refresh token may be expired or revoked: {0:?}")]
+    InvalidGrant(Option<String>),
+    #[error("invalid scope: {0:?}")]
+    InvalidScope(Option<String>),
+    #[error("invalid client: {0:?}")]
+    InvalidClient(Option<String>),
+    #[error("response body exceeds the limit of {0} bytes")]
+    ResponseTooLarge(usize),
+    #[error("response body decode error: {0}")]
+    ContentEncoding(&'static str),
+    #[error("response content type is not json: {0:?}")]
+    UnexpectedContentType(String),
     #[error("response body deserialize error: {0}")]
     JsonDeserialize(serde_json::Error),
+    #[error("clock skew detected, adjusted the clock offset to {0} seconds")]
+    ClockSkew(i64),
+    #[error("private key error: {0}")]
+    PrivateKey(jsonwebtoken::errors::Error),
+    #[error("reserved jwt claim: {0}")]
+    ReservedClaim(String),
+    #[error("reserved header: {0}")]
+    ReservedHeader(String),
+    #[error("gcloud command error: {0}")]
+    GcloudCommand(std::io::Error),
+    #[error("gcloud command exited with {status}: {stderr}")]
+    GcloudStatus { status: std::process::ExitStatus, stderr: String },
+    #[error("api key uri error: {0}")]
+    ApiKeyUri(hyper::http::Error),
+    #[error("api key header error: {0}")]
+    ApiKeyHeader(hyper::header::InvalidHeaderValue),
+    #[error("credentials do not support id tokens")]
+    IdTokenUnsupported,
+    #[error("credentials do not use oauth 2.0 tokens")]
+    TokenUnsupported,
+    #[error("token was not granted the requested scopes: {0:?}")]
+    MissingScopes(Vec<String>),
+    #[error("request has no host to derive the id token audience from")]
+    MissingAudience,
+    #[error("custom fetcher error: {0}")]
+    Custom(Box<dyn std::error::Error + Send + Sync>),
+    #[error("token fetch timed out")]
+    Timeout,
+    #[error("token was fetched too recently, the next fetch is allowed in {0:?}")]
+    RefreshRateLimited(std::time::Duration),
+    #[error("token fetch error: {0}")]
+    Shared(Arc<Error>),
     #[error("token format error: {0:?}")]
     TokenFormat(crate::auth::oauth2::token::Response),
-    #[cfg(not(feature = "tonic"))]
     #[error("uri schema error: {0:?}")]
     EnforceHttps(Option<String>),
 }
 
+impl Error {
+    /// Returns the error that failed a fetch shared by several callers, or `self` otherwise.
+    /// Errors that cannot be cloned are reported to each of those callers as
+    /// [`Shared`](Self::Shared), e.g. network errors.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Self::Shared(err) => err.inner(),
+            _ => self,
+        }
+    }
+
+    // Returns the error of a fetch shared by several callers. Every caller gets the same variant,
+    // regardless of the order they are woken in: a copy if the error can be cloned, otherwise
+    // `Shared`.
+    pub(crate) fn shared(err: Arc<Error>) -> Error {
+        match *err {
+            Self::InvalidGrant(ref description) => Self::InvalidGrant(description.clone()),
+            Self::InvalidScope(ref description) => Self::InvalidScope(description.clone()),
+            Self::InvalidClient(ref description) => Self::InvalidClient(description.clone()),
+            Self::ResponseTooLarge(limit) => Self::ResponseTooLarge(limit),
+            Self::ContentEncoding(reason) => Self::ContentEncoding(reason),
+            Self::UnexpectedContentType(ref content_type) => {
+                Self::UnexpectedContentType(content_type.clone())
+            }
+            Self::ClockSkew(offset) => Self::ClockSkew(offset),
+            Self::ReservedClaim(ref claim) => Self::ReservedClaim(claim.clone()),
+            Self::ReservedHeader(ref header) => Self::ReservedHeader(header.clone()),
+            Self::GcloudStatus { status, ref stderr } => {
+                Self::GcloudStatus { status, stderr: stderr.clone() }
+            }
+            Self::IdTokenUnsupported => Self::IdTokenUnsupported,
+            Self::TokenUnsupported => Self::TokenUnsupported,
+            Self::MissingScopes(ref scopes) => Self::MissingScopes(scopes.clone()),
+            Self::MissingAudience => Self::MissingAudience,
+            Self::Timeout => Self::Timeout,
+            Self::RefreshRateLimited(wait) => Self::RefreshRateLimited(wait),
+            Self::TokenFormat(ref resp) => Self::TokenFormat(resp.clone()),
+            Self::EnforceHttps(ref scheme) => Self::EnforceHttps(scheme.clone()),
+            _ => Self::Shared(err),
+        }
+    }
+
+    /// Reports whether the error is likely to go away by itself, e.g. network errors, timeouts,
+    /// and server errors or rate limiting of the token endpoint. Retrying later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Gcemeta(gcemeta::Error::Http(_)) => true,
+            Self::Gcemeta(gcemeta::Error::StatusCode((parts, _))) => {
+                is_transient_status(parts.status)
+            }
+            Self::Http(_) | Self::Timeout | Self::RefreshRateLimited(_) | Self::ClockSkew(_) => {
+                true
+            }
+            #[cfg(feature = "reqwest")]
+            Self::Reqwest(err) => !err.is_builder(),
+            Self::StatusCode((parts, _)) => is_transient_status(parts.status),
+            Self::Shared(err) => err.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Reports whether the error is caused by the credentials or the configuration, e.g. an
+    /// expired refresh token, a denied scope, or a request over plain HTTP. It does not go away
+    /// until someone fixes the setup.
+    pub fn is_auth_config_error(&self) -> bool {
+        match self {
+            Self::InvalidGrant(_)
+            | Self::InvalidScope(_)
+            | Self::InvalidClient(_)
+            | Self::PrivateKey(_)
+            | Self::ReservedClaim(_)
+            | Self::ReservedHeader(_)
+            | Self::ApiKeyUri(_)
+            | Self::ApiKeyHeader(_)
+            | Self::IdTokenUnsupported
+            | Self::TokenUnsupported
+            | Self::MissingScopes(_)
+            | Self::MissingAudience
+            | Self::EnforceHttps(_)
+            | Self::Gcemeta(gcemeta::Error::Uri(_)) => true,
+            Self::StatusCode((parts, _)) => {
+                matches!(parts.status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+            }
+            Self::Shared(err) => err.is_auth_config_error(),
+            _ => false,
+        }
+    }
+
+    // Retrying does not help for errors that require reauthentication or reconfiguration.
+    pub(crate) fn is_retryable(&self) -> bool {
+        !matches!(
+            self.inner(),
+            Self::InvalidGrant(_)
+                | Self::InvalidScope(_)
+                | Self::InvalidClient(_)
+                | Self::PrivateKey(_)
+                | Self::ReservedClaim(_)
+                | Self::IdTokenUnsupported
+                | Self::MissingScopes(_)
+        )
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
 /// Wrapper for the `Result` type with an [`Error`](Error).
 pub(crate) type Result<T> = std::result::Result<T, Error>;
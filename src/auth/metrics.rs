@@ -0,0 +1,23 @@
+use std::fmt;
+
+use crate::auth::Error;
+
+/// Receives token fetch events so that they can be exported as counters.
+///
+/// All methods have empty default implementations, so only the events of interest need to be
+/// implemented.
+pub trait Metrics: fmt::Debug + Send + Sync + 'static {
+    /// Called each time a token fetch is started, including retries.
+    fn fetch_attempt(&self) {}
+
+    /// Called when a token is fetched successfully.
+    fn fetch_success(&self) {}
+
+    /// Called when a token fetch attempt fails.
+    fn fetch_failure(&self, _err: &Error) {}
+
+    /// Called when a failed token fetch is retried.
+    fn fetch_retry(&self) {}
+}
+
+impl Metrics for () {}
@@ -1,29 +1,233 @@
-use std::task::{self, Poll};
+use std::{
+    fmt,
+    net::IpAddr,
+    sync::Arc,
+    task::{self, Poll},
+    time::Duration,
+};
 
-use hyper::Request;
+use arc_swap::{ArcSwap, Guard};
+use futures_util::future;
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    http::response::Parts,
+    Request,
+};
+use tracing::{info, warn};
 
 use crate::Credentials;
 
-mod api_key;
+pub(crate) mod api_key;
 mod error;
+mod metrics;
 mod oauth2;
 
 pub use error::*;
-use oauth2::{token::Fetcher, Metadata, Oauth2, ServiceAccount, User};
+pub use metrics::Metrics;
+#[cfg(not(feature = "openssl"))]
+pub use oauth2::tls_roots;
+pub(crate) use oauth2::{
+    access_secret, check_extra_claims, fetch_user_email, MetadataHost, USERINFO_EMAIL_SCOPE,
+};
+pub use oauth2::{
+    token::{AccessToken, Fetcher, Response as TokenResponse, ResponseFuture},
+    MockFetcher,
+};
+use oauth2::{
+    ClientCredentials, GcloudCli, GrantedScopes, Impersonated, Metadata, Oauth2, ServiceAccount,
+    TokenExchange, User, Watched,
+};
+
+// https://developers.google.com/identity/protocols/oauth2/service-account#authorizingrequests
+pub(crate) const MAX_JWT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// Token responses, including ID tokens, are a few kilobytes at most.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// HTTP versions used by the client that fetches tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1 only.
+    Http1,
+    /// HTTP/2 only.
+    #[default]
+    Http2,
+    /// Negotiates HTTP/1.1 or HTTP/2 via ALPN.
+    All,
+}
+
+/// IP address families used by the client that fetches tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// IPv4 only.
+    V4,
+    /// IPv6 only.
+    V6,
+    /// Both, trying the other family after 300 ms if the first one does not connect
+    /// ("happy eyeballs").
+    #[default]
+    Both,
+}
+
+/// When a cached token is refreshed before it expires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefreshThreshold {
+    /// Refreshes when the remaining lifetime is less than the duration.
+    Delta(Duration),
+    /// Refreshes when the fraction of the lifetime has elapsed, e.g. `0.75` refreshes a one-hour
+    /// token after 45 minutes. The fraction is in the range `0.0..=1.0`.
+    Fraction(f64),
+}
+
+impl RefreshThreshold {
+    /// Returns the remaining lifetime at which a token with `lifetime` is refreshed.
+    pub(crate) fn delta(self, lifetime: Duration) -> Duration {
+        match self {
+            Self::Delta(delta) => delta,
+            Self::Fraction(fraction) => lifetime.mul_f64(1.0 - fraction),
+        }
+    }
+}
+
+impl Default for RefreshThreshold {
+    fn default() -> Self {
+        Self::Delta(Duration::from_secs(10))
+    }
+}
+
+/// How scopes are joined in a token request. Each flow uses the delimiter its endpoint expects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScopeDelimiter {
+    /// `scope1 scope2`, as in OAuth 2.0 (RFC 6749).
+    #[default]
+    Space,
+    /// `scope1,scope2`, as in the metadata server.
+    Comma,
+}
+
+impl ScopeDelimiter {
+    pub(crate) fn join(self, scopes: &[String]) -> String {
+        match self {
+            Self::Space => scopes.join(" "),
+            Self::Comma => scopes.join(","),
+        }
+    }
+}
+
+/// The root certificates trusted by the client that fetches tokens, selected by the
+/// `native-certs` or `webpki-roots` feature. `native-certs` wins if both are enabled. With the
+/// `openssl` feature, the system OpenSSL verifies servers and the roots are not reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootStore {
+    /// The certificates of the platform, read by `rustls-native-certs`.
+    Native,
+    /// The Mozilla root certificates compiled in by `webpki-roots`.
+    WebpkiRoots,
+}
+
+/// Reports the root certificates used by the client that fetches tokens, returned by
+/// [`tls_roots`](crate::tls_roots), e.g. to diagnose `UnknownIssuer` errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlsRoots {
+    pub store: RootStore,
+    /// The number of usable root certificates. It is zero if the platform certificates cannot be
+    /// read, e.g. in minimal containers without a CA bundle.
+    pub count: usize,
+}
+
+// `rustls::ClientConfig` does not implement `Debug`.
+#[cfg(not(feature = "openssl"))]
+#[derive(Clone)]
+pub(crate) struct TlsConfig(pub rustls::ClientConfig);
+
+#[cfg(not(feature = "openssl"))]
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish()
+    }
+}
+
+// Called with the status and headers of each response of token endpoints.
+#[derive(Clone)]
+pub(crate) struct ResponseHook(pub Arc<dyn Fn(&Parts) + Send + Sync>);
+
+impl fmt::Debug for ResponseHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseHook").finish()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
-    #[cfg(not(feature = "tonic"))]
     pub enforce_https: bool,
+    pub api_key_in_header: bool,
     pub max_retry: u8,
+    pub metadata_retry: u8,
+    pub fetch_failure_level: tracing::Level,
+    pub fetch_timeout: Option<Duration>,
+    pub max_response_size: usize,
+    pub refresh_threshold: RefreshThreshold,
+    pub expiry_jitter: Duration,
+    pub serve_stale: Option<Duration>,
+    pub min_refresh_interval: Option<Duration>,
+    pub user_agent: Option<String>,
+    pub quota_project: Option<String>,
+    pub extra_headers: HeaderMap,
+    pub http_version: HttpVersion,
+    #[cfg(not(feature = "openssl"))]
+    pub tls_config: Option<TlsConfig>,
+    pub local_address: Option<IpAddr>,
+    pub address_family: AddressFamily,
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub jwt_lifetime: Duration,
+    pub jwt_audiences: Vec<String>,
+    pub extra_jwt_claims: serde_json::Map<String, serde_json::Value>,
+    pub sync_clock: bool,
+    pub strict_scopes: bool,
+    pub watch_credentials: bool,
+    pub log_token_fingerprint: bool,
+    #[cfg(feature = "reqwest")]
+    pub reqwest_client: Option<reqwest::Client>,
+    pub metrics: Arc<dyn Metrics>,
+    pub on_fetch_response: Option<ResponseHook>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            #[cfg(not(feature = "tonic"))]
             enforce_https: true,
+            api_key_in_header: false,
             max_retry: 3,
+            metadata_retry: 3,
+            fetch_failure_level: tracing::Level::ERROR,
+            fetch_timeout: None,
+            max_response_size: MAX_RESPONSE_SIZE,
+            refresh_threshold: RefreshThreshold::default(),
+            expiry_jitter: Duration::ZERO,
+            serve_stale: Some(Duration::ZERO),
+            min_refresh_interval: None,
+            user_agent: None,
+            quota_project: None,
+            extra_headers: HeaderMap::new(),
+            http_version: HttpVersion::default(),
+            #[cfg(not(feature = "openssl"))]
+            tls_config: None,
+            local_address: None,
+            address_family: AddressFamily::default(),
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            jwt_lifetime: MAX_JWT_LIFETIME,
+            jwt_audiences: Vec::new(),
+            extra_jwt_claims: Default::default(),
+            sync_clock: false,
+            strict_scopes: false,
+            watch_credentials: false,
+            log_token_fingerprint: false,
+            #[cfg(feature = "reqwest")]
+            reqwest_client: None,
+            metrics: Arc::new(()),
+            on_fetch_response: None,
         }
     }
 }
@@ -37,62 +241,349 @@ enum Inner {
 
 impl From<(Credentials, &Config)> for Inner {
     fn from((credentials, config): (Credentials, &Config)) -> Self {
-        let fetcher: Box<dyn Fetcher> = match credentials {
-            Credentials::None => return Self::None,
-            Credentials::ApiKey(key) => return Self::ApiKey(api_key::ApiKey::new(key)),
-            Credentials::User(user) => Box::new(User::new(user)),
-            Credentials::ServiceAccount(sa) => Box::new(ServiceAccount::new(sa)),
-            Credentials::Metadata(meta) => Box::new(Metadata::new(meta)),
-        };
-        Self::Oauth2(Oauth2::new(fetcher, config.max_retry))
+        match credentials {
+            Credentials::None => Self::None,
+            Credentials::ApiKey(key) => {
+                Self::ApiKey(api_key::ApiKey::new(key, config.api_key_in_header))
+            }
+            credentials => {
+                let scopes = credentials.scopes().to_vec();
+                let fetcher = Box::new(GrantedScopes::new(
+                    fetcher(credentials, config),
+                    scopes,
+                    config.strict_scopes,
+                ));
+                Self::Oauth2(Oauth2::new(fetcher, config))
+            }
+        }
     }
 }
 
-// https://cloud.google.com/docs/authentication
+fn fetcher(credentials: Credentials, config: &Config) -> Box<dyn Fetcher> {
+    if config.watch_credentials {
+        if let Some(path) = credentials.path() {
+            let path = path.to_owned();
+            return Box::new(Watched::new(credentials, path, config));
+        }
+    }
+    plain_fetcher(credentials, config)
+}
+
+// Same as `fetcher`, but the credentials are never reloaded.
+fn plain_fetcher(credentials: Credentials, config: &Config) -> Box<dyn Fetcher> {
+    match credentials {
+        Credentials::User(user) => Box::new(User::new(user, config)),
+        Credentials::ServiceAccount(sa) => Box::new(ServiceAccount::new(sa, config)),
+        Credentials::Metadata(meta) => Box::new(Metadata::new(meta, config)),
+        Credentials::GcloudCli => Box::new(GcloudCli::new()),
+        Credentials::Fetcher(fetcher) => fetcher,
+        Credentials::TokenExchange(te) => Box::new(TokenExchange::new(*te, config)),
+        Credentials::ClientCredentials(cc) => Box::new(ClientCredentials::new(*cc, config)),
+        Credentials::Impersonated(imp) => {
+            let imp = *imp;
+            let source = fetcher(imp.source, config);
+            Box::new(Impersonated::new(
+                source,
+                imp.target_principal,
+                imp.scopes,
+                imp.id_token,
+                config,
+            ))
+        }
+        // The source credentials of impersonation are checked when they are built.
+        Credentials::None | Credentials::ApiKey(_) => {
+            unreachable!("credentials without OAuth 2.0 tokens")
+        }
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("inner", &self.inner)
+            .field("derived", &self.derived)
+            .field("enforce_https", &self.enforce_https)
+            .field("quota_project", &self.quota_project)
+            .field("extra_headers", &self.extra_headers)
+            .field("log_token_fingerprint", &self.log_token_fingerprint)
+            .finish()
+    }
+}
+
+// Tokens of a handle that are not for the scopes of the credentials.
 #[derive(Clone, Debug)]
+enum Derived {
+    Scopes(Vec<String>),
+    Audience(String),
+}
+
+impl Derived {
+    fn apply(&self, inner: &Inner) -> Inner {
+        match (inner, self) {
+            (Inner::Oauth2(oauth2), Self::Scopes(scopes)) => {
+                Inner::Oauth2(oauth2.with_scopes(scopes.clone()))
+            }
+            (Inner::Oauth2(oauth2), Self::Audience(audience)) => {
+                Inner::Oauth2(oauth2.with_audience(audience.clone()))
+            }
+            (inner, _) => inner.clone(),
+        }
+    }
+}
+
+// https://cloud.google.com/apis/docs/system-parameters
+const QUOTA_PROJECT: HeaderName = HeaderName::from_static("x-goog-user-project");
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// https://cloud.google.com/docs/authentication
+#[derive(Clone)]
 pub(crate) struct Auth {
     inner: Inner,
-    #[cfg(not(feature = "tonic"))]
+    // The credentials shared with the clones, replaced by `set_credentials`.
+    shared: Arc<ArcSwap<Inner>>,
+    // The shared credentials that `inner` is made from.
+    base: Arc<Inner>,
+    // Applied to the credentials that replace `base` too.
+    derived: Option<Derived>,
+    config: Arc<Config>,
     enforce_https: bool,
+    quota_project: Option<HeaderValue>,
+    extra_headers: HeaderMap,
+    log_token_fingerprint: bool,
 }
 
 impl Auth {
     pub fn new(credentials: Credentials, config: Config) -> Self {
+        let quota_project = config.quota_project.as_deref().and_then(|project| {
+            HeaderValue::from_str(project)
+                .map_err(|err| warn!("invalid quota project, ignore it: {:?}", err))
+                .ok()
+        });
+        let base = Arc::new(Inner::from((credentials, &config)));
         Self {
-            inner: (credentials, &config).into(),
-            #[cfg(not(feature = "tonic"))]
+            inner: Inner::clone(&base),
+            shared: Arc::new(ArcSwap::new(base.clone())),
+            base,
+            derived: None,
             enforce_https: config.enforce_https,
+            quota_project,
+            extra_headers: config.extra_headers.clone(),
+            log_token_fingerprint: config.log_token_fingerprint,
+            config: Arc::new(config),
         }
     }
 
+    pub fn with_scopes(&self, scopes: Vec<String>) -> Self {
+        self.derive(Derived::Scopes(scopes))
+    }
+
+    pub fn with_audience(&self, audience: String) -> Self {
+        self.derive(Derived::Audience(audience))
+    }
+
+    fn derive(&self, derived: Derived) -> Self {
+        Self { inner: derived.apply(&self.inner), derived: Some(derived), ..self.clone() }
+    }
+
+    /// Replaces the credentials of this value and its clones, which use the new ones from their
+    /// next `poll_ready`. The configuration is kept.
+    pub fn set_credentials(&self, credentials: Credentials) {
+        self.shared.store(Arc::new((credentials, &*self.config).into()));
+    }
+
+    // Switches to the credentials set by `set_credentials`, if any.
+    fn sync(&mut self) {
+        let current = self.shared.load();
+        if Arc::ptr_eq(&self.base, &current) {
+            return;
+        }
+        self.base = Guard::into_inner(current);
+        self.inner = match self.derived {
+            Some(ref derived) => derived.apply(&self.base),
+            None => Inner::clone(&self.base),
+        };
+    }
+
     #[inline]
     pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<()>> {
+        self.sync();
         match self.inner {
             Inner::Oauth2(ref mut oauth2) => oauth2.poll_ready(cx),
             _ => Poll::Ready(Ok(())),
         }
     }
 
+    pub async fn token(&mut self) -> Result<Option<HeaderValue>> {
+        future::poll_fn(|cx| self.poll_ready(cx)).await?;
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => Ok(Some(oauth2.value())),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn access_token(&mut self) -> Result<Option<AccessToken>> {
+        future::poll_fn(|cx| self.poll_ready(cx)).await?;
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => Ok(Some(oauth2.access_token())),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn invalidate_token(&self) {
+        if let Inner::Oauth2(ref oauth2) = self.inner {
+            oauth2.invalidate();
+        }
+    }
+
+    // The `Authorization` header value `call` adds, if the credentials use a cached token.
+    pub fn token_value(&self) -> Option<HeaderValue> {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.cached_value(),
+            _ => None,
+        }
+    }
+
+    // Invalidates the token only if it is still `value`, e.g. the token of a rejected request, so
+    // that concurrent rejections of the same token refresh it once.
+    pub fn invalidate_token_value(&self, value: &HeaderValue) {
+        if let Inner::Oauth2(ref oauth2) = self.inner {
+            oauth2.invalidate_value(value);
+        }
+    }
+
+    pub fn token_ttl(&self) -> Option<Duration> {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.ttl(),
+            _ => None,
+        }
+    }
+
+    pub fn is_token_valid(&self) -> bool {
+        // Replaced credentials are used from the next `poll_ready`.
+        if !Arc::ptr_eq(&self.base, &self.shared.load()) {
+            return false;
+        }
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => oauth2.is_valid(),
+            Inner::None | Inner::ApiKey(_) => true,
+        }
+    }
+
     #[inline]
-    pub fn call<B>(&self, req: Request<B>) -> Result<Request<B>> {
-        #[cfg(not(feature = "tonic"))]
+    pub fn call<B>(&self, mut req: Request<B>) -> Result<Request<B>> {
         if self.enforce_https {
             check_https(req.uri().scheme_str())?;
         }
+        self.add_common_headers(req.headers_mut());
 
         match self.inner {
             Inner::None => Ok(req),
-            Inner::ApiKey(ref key) => Ok(key.add_query(req)),
+            Inner::ApiKey(ref key) => key.add_key(req),
+            Inner::Oauth2(ref oauth2) if self.log_token_fingerprint => {
+                add_logged_header(oauth2, req.headers_mut());
+                Ok(req)
+            }
             Inner::Oauth2(ref oauth2) => Ok(oauth2.add_header(req)),
         }
     }
+
+    // Like `call` but for requests that have no URI, e.g. gRPC calls seen by interceptors, so
+    // that the scheme is not checked and API keys are always sent in the header.
+    #[cfg_attr(not(feature = "tonic"), allow(dead_code))]
+    pub fn add_headers(&self, headers: &mut HeaderMap) -> Result<()> {
+        self.add_common_headers(headers);
+        match self.inner {
+            Inner::None => Ok(()),
+            Inner::ApiKey(ref key) => key.add_key_header(headers),
+            Inner::Oauth2(ref oauth2) if self.log_token_fingerprint => {
+                add_logged_header(oauth2, headers);
+                Ok(())
+            }
+            Inner::Oauth2(ref oauth2) => {
+                headers.insert(AUTHORIZATION, oauth2.value());
+                Ok(())
+            }
+        }
+    }
+
+    fn add_common_headers(&self, headers: &mut HeaderMap) {
+        if let Some(ref project) = self.quota_project {
+            headers.insert(QUOTA_PROJECT, project.clone());
+        }
+        if !self.extra_headers.is_empty() {
+            headers.extend(self.extra_headers.clone());
+        }
+    }
+}
+
+// Adds the token, logging its fingerprint with the `X-Request-Id` of the request, if it has one,
+// so that the log line can be matched with the request later. The request is not modified
+// otherwise.
+fn add_logged_header(oauth2: &Oauth2, headers: &mut HeaderMap) {
+    let value = oauth2.value();
+    match headers.get(REQUEST_ID) {
+        Some(request_id) => info!(
+            "authorize request: token_fingerprint={} request_id={}",
+            fingerprint(&value),
+            String::from_utf8_lossy(request_id.as_bytes())
+        ),
+        None => info!("authorize request: token_fingerprint={}", fingerprint(&value)),
+    }
+    headers.insert(AUTHORIZATION, value);
+}
+
+// FNV-1a, so that the fingerprint of a token is the same across processes and builds. It tells
+// tokens apart in logs without revealing them.
+fn fingerprint(value: &HeaderValue) -> String {
+    let hash = value.as_bytes().iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+    });
+    format!("{:016x}", hash)
 }
 
 #[inline]
-#[cfg(not(feature = "tonic"))]
 fn check_https(scheme: Option<&'_ str>) -> Result<()> {
     match scheme {
         Some("https") => Ok(()),
         _ => Err(Error::EnforceHttps(scheme.map(ToOwned::to_owned))),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_log_token_fingerprint() {
+        assert_eq!(fingerprint(&HeaderValue::from_static("a")), "af63dc4c8601ec8c");
+
+        let fetcher = MockFetcher::new("token", Duration::from_secs(3600));
+        let credentials = Credentials::builder().fetcher(fetcher).build().await.unwrap();
+        let config = Config { log_token_fingerprint: true, ..Default::default() };
+        let mut auth = Auth::new(credentials, config);
+        future::poll_fn(|cx| auth.poll_ready(cx)).await.unwrap();
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        let req = auth.call(req).unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+        assert!(!req.headers().contains_key(REQUEST_ID));
+
+        let req = Request::get("https://example.com/").header(REQUEST_ID, "id").body(()).unwrap();
+        let req = auth.call(req).unwrap();
+        assert_eq!(req.headers()[REQUEST_ID], "id");
+    }
+
+    #[test]
+    fn test_extra_headers() {
+        let mut extra_headers = HeaderMap::new();
+        extra_headers.insert("x-goog-request-reason", HeaderValue::from_static("debugging"));
+        let config = Config { extra_headers, ..Default::default() };
+        let auth = Auth::new(Credentials::ApiKey("api-key".into()), config);
+        let req = Request::get("https://example.com/")
+            .header("x-goog-request-reason", "overridden")
+            .body(())
+            .unwrap();
+        let req = auth.call(req).unwrap();
+        assert_eq!(req.headers()["x-goog-request-reason"], "debugging");
+        assert_eq!(req.uri(), "https://example.com/?key=api-key");
+    }
+}
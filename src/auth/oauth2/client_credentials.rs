@@ -0,0 +1,79 @@
+use std::fmt;
+
+use hyper::Uri;
+use tracing::trace;
+
+use crate::{
+    auth::{
+        oauth2::{http::Client, token},
+        Config,
+    },
+    credentials,
+};
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    grant_type: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+}
+
+// https://datatracker.ietf.org/doc/html/rfc6749#section-4.4
+pub struct ClientCredentials {
+    inner: Client,
+    token_uri: Uri,
+    credentials: credentials::ClientCredentials,
+}
+
+impl ClientCredentials {
+    pub(crate) fn new(cc: credentials::ClientCredentials, config: &Config) -> Self {
+        Self {
+            inner: Client::new(config),
+            // Already checked when the credentials were built.
+            token_uri: Uri::from_maybe_shared(cc.token_uri.clone()).unwrap(),
+            credentials: cc,
+        }
+    }
+}
+
+impl fmt::Debug for ClientCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCredentials").field("token_uri", &self.token_uri).finish()
+    }
+}
+
+impl token::Fetcher for ClientCredentials {
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let scopes = scopes.unwrap_or(&self.credentials.scopes);
+        let scope = (!scopes.is_empty()).then(|| self.credentials.scope_delimiter.join(scopes));
+        trace!("fetch client credentials token: scope={:?}", scope);
+        let req = self.inner.request(&self.token_uri, &Payload {
+            grant_type: credentials::CLIENT_CREDENTIALS_GRANT_TYPE,
+            client_id: &self.credentials.client_id,
+            client_secret: &self.credentials.client_secret,
+            scope: scope.as_deref(),
+        });
+        Box::pin(self.inner.send(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let payload = Payload {
+            grant_type: credentials::CLIENT_CREDENTIALS_GRANT_TYPE,
+            client_id: "client-id",
+            client_secret: "a&b",
+            scope: Some("scope1 scope2"),
+        };
+        assert_eq!(
+            serde_urlencoded::to_string(&payload).unwrap(),
+            "grant_type=client_credentials&client_id=client-id&client_secret=a%26b&scope=scope1+scope2"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fmt, time::SystemTime};
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::process::Command;
+
+use crate::auth::{self, oauth2::token};
+
+#[cfg(target_os = "windows")]
+const GCLOUD: &str = "gcloud.cmd";
+#[cfg(not(target_os = "windows"))]
+const GCLOUD: &str = "gcloud";
+
+#[derive(serde::Deserialize)]
+struct Output {
+    token: String,
+    expiry: String,
+}
+
+// https://cloud.google.com/sdk/gcloud/reference/auth/print-access-token
+pub struct GcloudCli {
+    program: String,
+}
+
+impl GcloudCli {
+    pub(crate) fn new() -> Self {
+        Self { program: GCLOUD.to_owned() }
+    }
+}
+
+impl fmt::Debug for GcloudCli {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcloudCli").field("program", &self.program).finish()
+    }
+}
+
+impl token::Fetcher for GcloudCli {
+    // The access token of the gcloud session is not scoped.
+    fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(["auth", "print-access-token", "--format=json"]).kill_on_drop(true);
+        Box::pin(async move {
+            let output = cmd.output().await.map_err(auth::Error::GcloudCommand)?;
+            if !output.status.success() {
+                return Err(auth::Error::GcloudStatus {
+                    status: output.status,
+                    stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+                });
+            }
+
+            let output = serde_json::from_slice::<Output>(&output.stdout)
+                .map_err(auth::Error::JsonDeserialize)?;
+            let now = SystemTime::now();
+            let expires_in = parse_rfc3339(&output.expiry)
+                .and_then(|expiry| expiry.duration_since(now).ok())
+                .map_or(0, |dur| dur.as_secs());
+            Ok(token::Response {
+                token_type: "Bearer".to_owned(),
+                access_token: output.token,
+                expires_in,
+                scope: None,
+            })
+        })
+    }
+}
+
+// Parses a timestamp such as `2006-01-02T15:04:05Z` or `2006-01-02T15:04:05.999+09:00`.
+pub(super) fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    OffsetDateTime::parse(s, &Rfc3339).ok().map(SystemTime::from)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), at(0));
+        assert_eq!(parse_rfc3339("2000-03-01T00:00:00Z"), at(951_868_800));
+        assert_eq!(
+            parse_rfc3339("2022-05-04T12:34:56.789Z"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_651_667_696_789))
+        );
+        assert_eq!(parse_rfc3339("2022-05-04T12:34:56+09:00"), at(1_651_635_296));
+        assert_eq!(parse_rfc3339("2022-13-04T12:34:56Z"), None);
+        assert_eq!(parse_rfc3339("invalid"), None);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use futures_util::FutureExt as _;
+use tracing::warn;
+
+use crate::auth::{self, oauth2::token};
+
+// Checks that the granted scopes of access tokens include the requested ones. ID tokens have no
+// scopes, so they are not checked.
+pub struct GrantedScopes {
+    inner: Box<dyn token::Fetcher>,
+    // The scopes of the credentials, requested unless they are overridden.
+    scopes: Vec<String>,
+    strict: bool,
+}
+
+impl GrantedScopes {
+    pub(crate) fn new(inner: Box<dyn token::Fetcher>, scopes: Vec<String>, strict: bool) -> Self {
+        Self { inner, scopes, strict }
+    }
+}
+
+impl fmt::Debug for GrantedScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrantedScopes").field("inner", &self.inner).finish()
+    }
+}
+
+impl token::Fetcher for GrantedScopes {
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let requested = scopes.unwrap_or(&self.scopes).to_vec();
+        let strict = self.strict;
+        Box::pin(self.inner.fetch(scopes).map(move |resp| {
+            let resp = resp?;
+            let missing = match resp.scope {
+                Some(ref granted) => missing_scopes(&requested, granted),
+                None => return Ok(resp),
+            };
+            if missing.is_empty() {
+                Ok(resp)
+            } else if strict {
+                Err(auth::Error::MissingScopes(missing))
+            } else {
+                warn!("token was not granted the requested scopes: {:?}", missing);
+                Ok(resp)
+            }
+        }))
+    }
+
+    fn fetch_id_token(&self, audience: &str) -> token::ResponseFuture {
+        self.inner.fetch_id_token(audience)
+    }
+}
+
+// Returns the requested scopes that are not in the space-delimited `granted`.
+fn missing_scopes(requested: &[String], granted: &str) -> Vec<String> {
+    let granted: Vec<_> = granted.split_whitespace().collect();
+    requested.iter().filter(|scope| !granted.contains(&scope.as_str())).cloned().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::future;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Granted(&'static str);
+
+    impl token::Fetcher for Granted {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            Box::pin(future::ready(Ok(token::Response {
+                token_type: "Bearer".into(),
+                access_token: "token".into(),
+                expires_in: 3600,
+                scope: Some(self.0.into()),
+            })))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_granted_scopes() {
+        let scopes = vec!["a".to_owned(), "b".to_owned()];
+        let fetcher = GrantedScopes::new(Box::new(Granted("b a")), scopes.clone(), true);
+        assert!(token::Fetcher::fetch(&fetcher, None).await.is_ok());
+
+        let fetcher = GrantedScopes::new(Box::new(Granted("a")), scopes.clone(), true);
+        let res = token::Fetcher::fetch(&fetcher, None).await;
+        assert!(matches!(res, Err(auth::Error::MissingScopes(missing)) if missing == ["b"]));
+        // Overridden scopes are checked instead of the ones of the credentials.
+        let res = token::Fetcher::fetch(&fetcher, Some(&["a".to_owned()])).await;
+        assert!(res.is_ok());
+
+        let fetcher = GrantedScopes::new(Box::new(Granted("a")), scopes, false);
+        assert!(token::Fetcher::fetch(&fetcher, None).await.is_ok());
+    }
+}
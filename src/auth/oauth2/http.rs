@@ -1,74 +1,629 @@
-use std::future::Future;
+use std::{
+    future::Future,
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
+    time::SystemTime,
+};
 
+use bytes::{Bytes, BytesMut};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use futures_util::FutureExt as _;
 use hyper::{
-    body::aggregate,
+    body::HttpBody as _,
     client::HttpConnector,
-    header::{HeaderValue, CONTENT_TYPE, USER_AGENT},
+    header::{
+        HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, DATE, USER_AGENT,
+    },
+    http::response::Parts,
     Body, Method, Request, StatusCode, Uri,
 };
+#[cfg(not(feature = "openssl"))]
 use hyper_rustls::{builderstates::WantsSchemes, HttpsConnector, HttpsConnectorBuilder};
+#[cfg(feature = "openssl")]
+use hyper_tls::HttpsConnector;
+use tracing::{trace, warn};
+
+use crate::auth::{self, AddressFamily, Config, HttpVersion, ResponseHook};
+#[cfg(not(feature = "openssl"))]
+use crate::auth::{RootStore, TlsConfig, TlsRoots};
+
+pub(super) type HyperClient = hyper::Client<HttpsConnector<HttpConnector>, Body>;
 
-use crate::auth;
+// `Sync`, so that futures that await responses, e.g. `credentials::Builder::build`, stay `Sync`.
+type ResponseFuture = Pin<Box<dyn Future<Output = auth::Result<(Parts, Bytes)>> + Send + Sync>>;
+
+// https://datatracker.ietf.org/doc/html/rfc6749#section-5.2
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
 
+#[derive(Clone)]
+enum Inner {
+    Hyper(HyperClient),
+    #[cfg(feature = "reqwest")]
+    Reqwest(reqwest::Client),
+}
+
+#[derive(Clone)]
 pub(super) struct Client {
-    inner: hyper::Client<HttpsConnector<HttpConnector>, Body>,
+    inner: Inner,
     user_agent: HeaderValue,
     content_type: HeaderValue,
+    max_response_size: usize,
+    on_response: Option<ResponseHook>,
 }
 
 impl Client {
-    pub fn new() -> Client {
-        let https = connection_builder().https_only().enable_http2().build();
-        let user_agent =
-            concat!("github.com/mechiru/", env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
+    pub fn new(config: &Config) -> Client {
+        #[cfg(feature = "reqwest")]
+        let inner = match config.reqwest_client {
+            Some(ref client) => Inner::Reqwest(client.clone()),
+            None => Inner::Hyper(hyper_client(config, true)),
+        };
+        #[cfg(not(feature = "reqwest"))]
+        let inner = Inner::Hyper(hyper_client(config, true));
         Self {
-            inner: hyper::Client::builder().build(https),
-            user_agent: HeaderValue::from_static(user_agent),
+            inner,
+            user_agent: user_agent(config.user_agent.as_deref()),
             content_type: HeaderValue::from_static("application/x-www-form-urlencoded"),
+            max_response_size: config.max_response_size,
+            on_response: config.on_fetch_response.clone(),
         }
     }
 
-    pub fn request<T>(&self, uri: &Uri, body: &T) -> Request<Body>
+    pub fn request<T>(&self, uri: &Uri, body: &T) -> Request<Bytes>
     where
         T: serde::Serialize,
     {
         let mut req = Request::builder().uri(uri).method(Method::POST);
         let headers = req.headers_mut().unwrap();
         headers.insert(USER_AGENT, self.user_agent.clone());
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
         headers.insert(CONTENT_TYPE, self.content_type.clone());
-        let body = Body::from(serde_urlencoded::to_string(body).unwrap());
+        let body = Bytes::from(serde_urlencoded::to_string(body).unwrap());
         req.body(body).unwrap()
     }
 
+    /// Same as [`request`](Self::request), but sends `body` as JSON with the `Authorization` header.
+    pub fn request_json<T>(&self, uri: &Uri, authorization: HeaderValue, body: &T) -> Request<Bytes>
+    where
+        T: serde::Serialize,
+    {
+        let mut req = Request::builder().uri(uri).method(Method::POST);
+        let headers = req.headers_mut().unwrap();
+        headers.insert(USER_AGENT, self.user_agent.clone());
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(AUTHORIZATION, authorization);
+        let body = Bytes::from(serde_json::to_vec(body).unwrap());
+        req.body(body).unwrap()
+    }
+
+    /// Returns a `GET` request to `uri` with the `Authorization` header.
+    pub fn get(&self, uri: &Uri, authorization: HeaderValue) -> Request<Bytes> {
+        let mut req = Request::builder().uri(uri).method(Method::GET);
+        let headers = req.headers_mut().unwrap();
+        headers.insert(USER_AGENT, self.user_agent.clone());
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(AUTHORIZATION, authorization);
+        req.body(Bytes::new()).unwrap()
+    }
+
     pub fn send<T>(
         &self,
-        req: Request<Body>,
-    ) -> impl Future<Output = auth::Result<T>> + Send + 'static
+        req: Request<Bytes>,
+    ) -> impl Future<Output = auth::Result<T>> + Send + Sync + 'static
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.send_with_date(req).map(|(resp, _)| resp)
+    }
+
+    /// Same as [`send`](Self::send), but also returns the `Date` header of the response.
+    pub fn send_with_date<T>(
+        &self,
+        req: Request<Bytes>,
+    ) -> impl Future<Output = (auth::Result<T>, Option<SystemTime>)> + Send + Sync + 'static
     where
         T: serde::de::DeserializeOwned,
     {
-        let fut = self.inner.request(req);
-        async {
-            use bytes::Buf as _;
-
-            let (parts, body) = fut.await?.into_parts();
-            match parts.status {
-                StatusCode::OK => {
-                    let buf = aggregate(body).await?;
-                    serde_json::from_reader(buf.reader()).map_err(auth::Error::JsonDeserialize)
-                }
-                _ => Err(auth::Error::StatusCode((parts, body))),
+        let fut = self.execute(req);
+        let on_response = self.on_response.clone();
+        async move {
+            let (parts, buf) = match fut.await {
+                Ok(resp) => resp,
+                Err(err) => return (Err(err), None),
+            };
+            if let Some(ResponseHook(ref f)) = on_response {
+                f(&parts);
+            }
+            let date = parts
+                .headers
+                .get(DATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| httpdate::parse_http_date(value).ok());
+            let resp = match parts.status {
+                StatusCode::OK => check_content_type(&parts).and_then(|()| {
+                    serde_json::from_slice(&buf).map_err(auth::Error::JsonDeserialize)
+                }),
+                _ => Err(status_error(parts, buf)),
+            };
+            (resp, date)
+        }
+    }
+
+    // The response body is aggregated only up to `max_response_size` bytes.
+    fn execute(&self, req: Request<Bytes>) -> ResponseFuture {
+        let limit = self.max_response_size;
+        match self.inner {
+            Inner::Hyper(ref client) => {
+                let fut = client.request(req.map(Body::from));
+                Box::pin(async move {
+                    let (parts, body) = fut.await?.into_parts();
+                    let buf = decode(&parts, read_body(body, limit).await?, limit)?;
+                    Ok((parts, buf))
+                })
+            }
+            #[cfg(feature = "reqwest")]
+            Inner::Reqwest(ref client) => {
+                use std::convert::TryFrom as _;
+
+                let fut = reqwest::Request::try_from(req).map(|req| client.execute(req));
+                Box::pin(async move {
+                    let mut resp = fut?.await?;
+                    let mut builder = hyper::Response::builder().status(resp.status());
+                    if let Some(headers) = builder.headers_mut() {
+                        *headers = resp.headers().clone();
+                    }
+                    let (parts, ()) = builder.body(()).unwrap().into_parts();
+                    let mut buf = Buffer::new(limit, resp.content_length().unwrap_or_default())?;
+                    while let Some(chunk) = resp.chunk().await? {
+                        buf.push(&chunk)?;
+                    }
+                    let buf = decode(&parts, buf.freeze(), limit)?;
+                    Ok((parts, buf))
+                })
             }
         }
     }
 }
 
-#[cfg(feature = "native-certs")]
+// Reads chunks as they arrive, so that a chunked body is rejected as soon as it exceeds `limit`
+// without reading the rest of it.
+pub(super) async fn read_body(mut body: Body, limit: usize) -> auth::Result<Bytes> {
+    let mut buf = Buffer::new(limit, body.size_hint().lower())?;
+    while let Some(chunk) = body.data().await {
+        buf.push(&chunk?)?;
+    }
+    Ok(buf.freeze())
+}
+
+struct Buffer {
+    buf: BytesMut,
+    limit: usize,
+}
+
+impl Buffer {
+    // Fails early if the expected size of the body already exceeds the limit.
+    fn new(limit: usize, size_hint: u64) -> auth::Result<Self> {
+        if size_hint > limit as u64 {
+            return Err(auth::Error::ResponseTooLarge(limit));
+        }
+        Ok(Self { buf: BytesMut::with_capacity(size_hint as usize), limit })
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> auth::Result<()> {
+        if self.buf.len() + chunk.len() > self.limit {
+            return Err(auth::Error::ResponseTooLarge(self.limit));
+        }
+        self.buf.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn freeze(self) -> Bytes {
+        self.buf.freeze()
+    }
+}
+
+// Proxies may compress responses, although token requests do not accept any encoding.
+fn decode(parts: &Parts, buf: Bytes, limit: usize) -> auth::Result<Bytes> {
+    match parts.headers.get(CONTENT_ENCODING) {
+        Some(encoding) => {
+            let encoding = encoding
+                .to_str()
+                .map_err(|_| auth::Error::ContentEncoding("invalid content encoding"))?;
+            inflate(encoding, buf, limit)
+        }
+        None => Ok(buf),
+    }
+}
+
+// Decodes `buf` compressed with `encoding`. The decoded body is limited to `limit` bytes, like
+// the compressed one.
+fn inflate(encoding: &str, buf: Bytes, limit: usize) -> auth::Result<Bytes> {
+    match encoding.trim().to_ascii_lowercase().as_str() {
+        "identity" => Ok(buf),
+        "gzip" | "x-gzip" => read_decoded(GzDecoder::new(&buf[..]), limit),
+        // `deflate` is the zlib format, but some servers send a raw deflate stream.
+        "deflate" => match read_decoded(ZlibDecoder::new(&buf[..]), limit) {
+            Err(auth::Error::ContentEncoding(_)) => {
+                read_decoded(DeflateDecoder::new(&buf[..]), limit)
+            }
+            res => res,
+        },
+        _ => Err(auth::Error::ContentEncoding("unsupported content encoding")),
+    }
+}
+
+// Reads one byte over `limit`, so that a larger body is detected without decoding the rest of it.
+fn read_decoded(decoder: impl Read, limit: usize) -> auth::Result<Bytes> {
+    let mut buf = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|_| auth::Error::ContentEncoding("invalid compressed body"))?;
+    if buf.len() > limit {
+        return Err(auth::Error::ResponseTooLarge(limit));
+    }
+    Ok(buf.into())
+}
+
+// Misconfigured proxies may answer with `200 OK` and an HTML page. Responses without a content
+// type are parsed as JSON.
+fn check_content_type(parts: &Parts) -> auth::Result<()> {
+    let content_type = match parts.headers.get(CONTENT_TYPE) {
+        Some(content_type) => String::from_utf8_lossy(content_type.as_bytes()),
+        None => return Ok(()),
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if essence == "application/json" || essence.ends_with("+json") {
+        Ok(())
+    } else {
+        Err(auth::Error::UnexpectedContentType(content_type.into_owned()))
+    }
+}
+
+/// Returns a client with the connection settings of the client that fetches tokens, for metadata
+/// servers at a custom host. It speaks HTTP/1.1, and also plain HTTP like `GCE_METADATA_HOST`.
+pub(super) fn metadata_client(config: &Config) -> HyperClient {
+    let config = Config { http_version: HttpVersion::Http1, ..config.clone() };
+    hyper_client(&config, false)
+}
+
+#[cfg(not(feature = "openssl"))]
+fn hyper_client(config: &Config, https_only: bool) -> HyperClient {
+    match config.tls_config {
+        Some(_) => trace!("use the custom tls config"),
+        // The roots are counted only if the event is enabled.
+        None => trace!("use the root certificates: {:?}", tls_roots()),
+    }
+    let builder = || {
+        let builder = match config.tls_config {
+            Some(TlsConfig(ref config)) => {
+                HttpsConnectorBuilder::new().with_tls_config(config.clone())
+            }
+            None => connection_builder(),
+        };
+        match https_only {
+            true => builder.https_only(),
+            false => builder.https_or_http(),
+        }
+    };
+    let http = http_connector(config);
+    let https = match config.http_version {
+        HttpVersion::Http1 => builder().enable_http1().wrap_connector(http),
+        HttpVersion::Http2 => builder().enable_http2().wrap_connector(http),
+        HttpVersion::All => builder().enable_http1().enable_http2().wrap_connector(http),
+    };
+    client_builder(config).build(https)
+}
+
+// hyper-tls does not report the protocol negotiated by ALPN, so HTTP/2 is used only if it is the
+// only version allowed. `HttpVersion::All` uses HTTP/1.1.
+#[cfg(feature = "openssl")]
+fn hyper_client(config: &Config, https_only: bool) -> HyperClient {
+    trace!("use the openssl tls connector");
+    let http2 = config.http_version == HttpVersion::Http2;
+    let mut https = HttpsConnector::from((http_connector(config), connection_builder(http2)));
+    https.https_only(https_only);
+    let mut builder = client_builder(config);
+    builder.http2_only(http2);
+    builder.build(https)
+}
+
+fn http_connector(config: &Config) -> HttpConnector {
+    let mut http = HttpConnector::new();
+    // `HttpsConnector` enforces the scheme.
+    http.enforce_http(false);
+    http.set_local_address(local_address(config));
+    http
+}
+
+fn client_builder(config: &Config) -> hyper::client::Builder {
+    let mut builder = hyper::Client::builder();
+    if let Some(timeout) = config.pool_idle_timeout {
+        builder.pool_idle_timeout(timeout);
+    }
+    if let Some(max_idle) = config.pool_max_idle_per_host {
+        builder.pool_max_idle_per_host(max_idle);
+    }
+    builder
+}
+
+// Binding the unspecified address of a family makes the connector skip the resolved addresses
+// of the other family.
+fn local_address(config: &Config) -> Option<IpAddr> {
+    match (config.local_address, config.address_family) {
+        (Some(addr), AddressFamily::V4) if !addr.is_ipv4() => {
+            warn!("local address is not ipv4, ignore the address family: {}", addr);
+            Some(addr)
+        }
+        (Some(addr), AddressFamily::V6) if !addr.is_ipv6() => {
+            warn!("local address is not ipv6, ignore the address family: {}", addr);
+            Some(addr)
+        }
+        (Some(addr), _) => Some(addr),
+        (None, AddressFamily::V4) => Some(Ipv4Addr::UNSPECIFIED.into()),
+        (None, AddressFamily::V6) => Some(Ipv6Addr::UNSPECIFIED.into()),
+        (None, AddressFamily::Both) => None,
+    }
+}
+
+fn status_error(parts: Parts, buf: Bytes) -> auth::Error {
+    match serde_json::from_slice::<ErrorResponse>(&buf) {
+        Ok(resp) => match resp.error.as_str() {
+            "invalid_grant" => auth::Error::InvalidGrant(resp.error_description),
+            "invalid_scope" => auth::Error::InvalidScope(resp.error_description),
+            "invalid_client" => auth::Error::InvalidClient(resp.error_description),
+            _ => auth::Error::StatusCode((parts, Body::from(buf))),
+        },
+        Err(_) => auth::Error::StatusCode((parts, Body::from(buf))),
+    }
+}
+
+const USER_AGENT_SUFFIX: &str =
+    concat!("github.com/mechiru/", env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
+
+// The crate identifier is always kept as a suffix so that Google can still see the library version.
+fn user_agent(prefix: Option<&str>) -> HeaderValue {
+    match prefix {
+        Some(prefix) => match HeaderValue::from_str(&format!("{} {}", prefix, USER_AGENT_SUFFIX)) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("invalid user agent, use the default one instead: {:?}", err);
+                HeaderValue::from_static(USER_AGENT_SUFFIX)
+            }
+        },
+        None => HeaderValue::from_static(USER_AGENT_SUFFIX),
+    }
+}
+
+#[cfg(all(feature = "native-certs", not(feature = "openssl")))]
 fn connection_builder() -> HttpsConnectorBuilder<WantsSchemes> {
     HttpsConnectorBuilder::new().with_native_roots()
 }
 
-#[cfg(all(not(feature = "native-certs"), feature = "webpki-roots"))]
+#[cfg(all(not(feature = "native-certs"), feature = "webpki-roots", not(feature = "openssl")))]
 fn connection_builder() -> HttpsConnectorBuilder<WantsSchemes> {
     HttpsConnectorBuilder::new().with_webpki_roots()
 }
+
+// The system OpenSSL verifies servers with its own default root certificates.
+#[cfg(feature = "openssl")]
+fn connection_builder(http2: bool) -> tokio_native_tls::TlsConnector {
+    let mut builder = native_tls::TlsConnector::builder();
+    if http2 {
+        builder.request_alpns(&["h2"]);
+    }
+    // Same as `hyper_tls::HttpsConnector::new`, which panics if OpenSSL cannot be initialized.
+    builder.build().expect("failed to initialize the openssl tls connector").into()
+}
+
+/// Returns the root certificates the client that fetches tokens trusts, unless a TLS
+/// configuration is set with `Builder::tls_config`. The platform certificates are read on each
+/// call, like when a client is built.
+#[cfg(all(feature = "native-certs", not(feature = "openssl")))]
+pub fn tls_roots() -> TlsRoots {
+    let certs = rustls_native_certs::load_native_certs().unwrap_or_else(|err| {
+        warn!("failed to load native root certificates: {}", err);
+        Vec::new()
+    });
+    let certs: Vec<_> = certs.into_iter().map(|cert| cert.0).collect();
+    let (count, _) = rustls::RootCertStore::empty().add_parsable_certificates(&certs);
+    TlsRoots { store: RootStore::Native, count }
+}
+
+/// Returns the root certificates the client that fetches tokens trusts, unless a TLS
+/// configuration is set with `Builder::tls_config`.
+#[cfg(all(not(feature = "native-certs"), feature = "webpki-roots", not(feature = "openssl")))]
+pub fn tls_roots() -> TlsRoots {
+    TlsRoots { store: RootStore::WebpkiRoots, count: webpki_roots::TLS_SERVER_ROOTS.0.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_local_address() {
+        let resolve = |addr: Option<IpAddr>, address_family| {
+            local_address(&Config { local_address: addr, address_family, ..Default::default() })
+        };
+        assert_eq!(resolve(None, AddressFamily::Both), None);
+        assert_eq!(resolve(None, AddressFamily::V4), Some(Ipv4Addr::UNSPECIFIED.into()));
+        assert_eq!(resolve(None, AddressFamily::V6), Some(Ipv6Addr::UNSPECIFIED.into()));
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        assert_eq!(resolve(Some(addr), AddressFamily::V4), Some(addr));
+        assert_eq!(resolve(Some(addr), AddressFamily::V6), Some(addr));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        assert_eq!(user_agent(None), USER_AGENT_SUFFIX);
+        assert_eq!(user_agent(Some("my-app/1.0")), format!("my-app/1.0 {}", USER_AGENT_SUFFIX));
+        assert_eq!(user_agent(Some("invalid\n")), USER_AGENT_SUFFIX);
+    }
+
+    #[cfg(not(feature = "openssl"))]
+    #[test]
+    fn test_tls_config() {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let config = Config { tls_config: Some(TlsConfig(tls_config)), ..Default::default() };
+        assert!(matches!(Client::new(&config).inner, Inner::Hyper(_)));
+    }
+
+    #[cfg(not(feature = "openssl"))]
+    #[test]
+    fn test_tls_roots() {
+        let roots = tls_roots();
+        #[cfg(feature = "native-certs")]
+        assert_eq!(roots.store, RootStore::Native);
+        #[cfg(all(not(feature = "native-certs"), feature = "webpki-roots"))]
+        assert!(roots.store == RootStore::WebpkiRoots && roots.count > 0);
+    }
+
+    #[test]
+    fn test_buffer() {
+        assert!(matches!(Buffer::new(4, 5), Err(auth::Error::ResponseTooLarge(4))));
+
+        let mut buf = Buffer::new(4, 0).unwrap();
+        buf.push(b"ab").unwrap();
+        buf.push(b"cd").unwrap();
+        assert!(matches!(buf.push(b"e"), Err(auth::Error::ResponseTooLarge(4))));
+        assert_eq!(buf.freeze(), Bytes::from_static(b"abcd"));
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let (mut tx, body) = Body::channel();
+        let sender = tokio::spawn(async move {
+            // The body never ends, like a hostile endpoint that streams forever.
+            while tx.send_data(Bytes::from_static(b"abcd")).await.is_ok() {}
+        });
+        assert!(matches!(read_body(body, 10).await, Err(auth::Error::ResponseTooLarge(10))));
+        sender.await.unwrap();
+
+        let body = Body::from("abcd");
+        assert_eq!(read_body(body, 4).await.unwrap(), Bytes::from_static(b"abcd"));
+        assert!(matches!(
+            read_body(Body::from("abcde"), 4).await,
+            Err(auth::Error::ResponseTooLarge(4))
+        ));
+    }
+
+    #[test]
+    fn test_inflate() {
+        fn hex(s: &str) -> Bytes {
+            (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+        }
+
+        let gzip = hex("1f8b080000000000020325cacb1180200c00d15632395b8116c3202046916804fc8dbdabe375df5ee84458b0468a4507b2ca8b8e09abbf2beb56233427e2f83ea977b0643223b4c25b848e7718f234afc0c5097c1cf4798065df00de0f346ba90d5d000000");
+        let text = r#"{"error":"invalid_grant","error_description":"the quick brown fox jumps over the lazy dog; "}"#;
+        assert_eq!(inflate("gzip", gzip.clone(), 1024).unwrap(), text.as_bytes());
+        assert!(matches!(
+            inflate("gzip", gzip.clone(), 16),
+            Err(auth::Error::ResponseTooLarge(16))
+        ));
+        let mut corrupted = gzip.to_vec();
+        corrupted[gzip.len() - 8] ^= 1;
+        assert!(matches!(
+            inflate("gzip", corrupted.into(), 1024),
+            Err(auth::Error::ContentEncoding(_))
+        ));
+
+        // The zlib and the raw formats.
+        let zlib = hex("78daab564a4c4e4e2d2e8e2fc9cf4ecd53b25282d03a4aa915059945a9c5f199404163534b4b1d884c7c4965412a5099536a62516a91522d00d8c51609");
+        let text = r#"{"access_token":"token","expires_in":3599,"token_type":"Bearer"}"#;
+        assert_eq!(inflate("deflate", zlib.clone(), 1024).unwrap(), text.as_bytes());
+        assert_eq!(
+            inflate("Deflate", zlib.slice(2..zlib.len() - 4), 1024).unwrap(),
+            text.as_bytes()
+        );
+        // A stored block.
+        assert_eq!(
+            inflate(
+                "deflate",
+                Bytes::from_static(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c']),
+                1024
+            )
+            .unwrap(),
+            &b"abc"[..]
+        );
+
+        assert_eq!(inflate("identity", Bytes::from_static(b"abc"), 1024).unwrap(), &b"abc"[..]);
+        assert!(matches!(
+            inflate("br", Bytes::from_static(b"abc"), 1024),
+            Err(auth::Error::ContentEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_status_error() {
+        let parts = || hyper::Response::builder().status(400).body(()).unwrap().into_parts().0;
+
+        let buf = Bytes::from_static(br#"{"error":"invalid_grant","error_description":"Bad"}"#);
+        assert!(
+            matches!(status_error(parts(), buf), auth::Error::InvalidGrant(Some(d)) if d == "Bad")
+        );
+
+        let buf = Bytes::from_static(br#"{"error":"invalid_scope"}"#);
+        assert!(matches!(status_error(parts(), buf), auth::Error::InvalidScope(None)));
+
+        let buf = Bytes::from_static(br#"{"error":"invalid_client"}"#);
+        assert!(matches!(status_error(parts(), buf), auth::Error::InvalidClient(None)));
+
+        let buf = Bytes::from_static(br#"{"error":"temporarily_unavailable"}"#);
+        assert!(matches!(status_error(parts(), buf), auth::Error::StatusCode(_)));
+
+        let buf = Bytes::from_static(b"<html></html>");
+        assert!(matches!(status_error(parts(), buf), auth::Error::StatusCode(_)));
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        let parts = |content_type: Option<&str>| {
+            let mut builder = hyper::Response::builder();
+            if let Some(content_type) = content_type {
+                builder = builder.header(CONTENT_TYPE, content_type);
+            }
+            builder.body(()).unwrap().into_parts().0
+        };
+        assert!(check_content_type(&parts(None)).is_ok());
+        assert!(check_content_type(&parts(Some("application/json"))).is_ok());
+        assert!(check_content_type(&parts(Some("Application/JSON; charset=utf-8"))).is_ok());
+        assert!(check_content_type(&parts(Some("application/problem+json"))).is_ok());
+        assert!(matches!(
+            check_content_type(&parts(Some("text/html; charset=utf-8"))),
+            Err(auth::Error::UnexpectedContentType(t)) if t == "text/html; charset=utf-8"
+        ));
+
+        let client = Client::new(&Config::default());
+        let req = client.request(&Uri::from_static("https://example.com/token"), &());
+        assert_eq!(req.headers()[ACCEPT], "application/json");
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let error = |status| {
+            let parts = hyper::Response::builder().status(status).body(()).unwrap().into_parts().0;
+            status_error(parts, Bytes::new())
+        };
+        assert!(error(503).is_transient());
+        assert!(error(429).is_transient());
+        assert!(!error(400).is_transient());
+        assert!(error(403).is_auth_config_error());
+
+        let buf = Bytes::from_static(br#"{"error":"invalid_grant"}"#);
+        let parts = hyper::Response::builder().status(400).body(()).unwrap().into_parts().0;
+        let err = status_error(parts, buf);
+        assert!(!err.is_transient() && err.is_auth_config_error());
+        let err = auth::Error::Shared(std::sync::Arc::new(auth::Error::Timeout));
+        assert!(err.is_transient() && !err.is_auth_config_error());
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use hyper::{header::HeaderValue, Uri};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{gcloud::parse_rfc3339, http::Client, token},
+        Config,
+    },
+    credentials,
+};
+
+#[derive(serde::Serialize)]
+struct AccessTokenRequest<'a> {
+    scope: &'a [String],
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessTokenResponse {
+    access_token: String,
+    expire_time: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IdTokenRequest<'a> {
+    audience: &'a str,
+    include_email: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct IdTokenResponse {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Claims {
+    exp: u64,
+}
+
+// https://cloud.google.com/iam/docs/create-short-lived-credentials-direct
+pub struct Impersonated {
+    inner: Client,
+    source: Arc<dyn token::Fetcher>,
+    access_token_uri: Uri,
+    id_token_uri: Uri,
+    scopes: Vec<String>,
+    id_token: Option<credentials::IdToken>,
+}
+
+impl Impersonated {
+    pub(crate) fn new(
+        source: Box<dyn token::Fetcher>,
+        target_principal: String,
+        scopes: Vec<String>,
+        id_token: Option<credentials::IdToken>,
+        config: &Config,
+    ) -> Self {
+        // Already checked that the target principal is valid as path string.
+        let uri = |method| {
+            let uri = format!(
+                "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/{}:{}",
+                target_principal, method
+            );
+            Uri::from_maybe_shared(uri).unwrap()
+        };
+        Self {
+            inner: Client::new(config),
+            source: source.into(),
+            access_token_uri: uri("generateAccessToken"),
+            id_token_uri: uri("generateIdToken"),
+            scopes,
+            id_token,
+        }
+    }
+
+    // Returns the `Authorization` header value of the source credentials.
+    fn authorization(&self) -> impl Future<Output = auth::Result<HeaderValue>> + Send + 'static {
+        let source = self.source.fetch(None);
+        async move {
+            let source = source.await?;
+            HeaderValue::from_str(&format!("{} {}", source.token_type, source.access_token))
+                .map_err(|_| auth::Error::TokenFormat(source))
+        }
+    }
+
+    fn generate_access_token(&self, scopes: &[String]) -> token::ResponseFuture {
+        let authorization = self.authorization();
+        let inner = self.inner.clone();
+        let uri = self.access_token_uri.clone();
+        let scopes = scopes.to_vec();
+        Box::pin(async move {
+            let req = inner
+                .request_json(&uri, authorization.await?, &AccessTokenRequest { scope: &scopes });
+            let resp = inner.send::<AccessTokenResponse>(req).await?;
+            let now = SystemTime::now();
+            let expires_in = parse_rfc3339(&resp.expire_time)
+                .and_then(|expiry| expiry.duration_since(now).ok())
+                .map_or(0, |dur| dur.as_secs());
+            Ok(token::Response {
+                token_type: "Bearer".to_owned(),
+                access_token: resp.access_token,
+                expires_in,
+                scope: None,
+            })
+        })
+    }
+
+    fn generate_id_token(&self, audience: &str, include_email: bool) -> token::ResponseFuture {
+        let authorization = self.authorization();
+        let inner = self.inner.clone();
+        let uri = self.id_token_uri.clone();
+        let audience = audience.to_owned();
+        Box::pin(async move {
+            let req = inner.request_json(&uri, authorization.await?, &IdTokenRequest {
+                audience: &audience,
+                include_email,
+            });
+            let resp = inner.send::<IdTokenResponse>(req).await?;
+            let expires_in = id_token_expires_in(&resp.token).unwrap_or_default();
+            Ok(token::Response {
+                token_type: "Bearer".to_owned(),
+                access_token: resp.token,
+                expires_in,
+                scope: None,
+            })
+        })
+    }
+}
+
+impl fmt::Debug for Impersonated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Impersonated").field("uri", &self.access_token_uri).finish()
+    }
+}
+
+impl token::Fetcher for Impersonated {
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        match self.id_token {
+            Some(ref id_token) => {
+                self.generate_id_token(&id_token.audience, id_token.include_email)
+            }
+            None => self.generate_access_token(scopes.unwrap_or(&self.scopes)),
+        }
+    }
+
+    fn fetch_id_token(&self, audience: &str) -> token::ResponseFuture {
+        let include_email = self.id_token.as_ref().is_some_and(|t| t.include_email);
+        self.generate_id_token(audience, include_email)
+    }
+}
+
+// The ID token is issued by Google over TLS, so only its `exp` claim is read.
+pub(super) fn id_token_expires_in(token: &str) -> Option<u64> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims = HashSet::new();
+    let claims =
+        jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation).ok()?;
+    let now = SystemTime::UNIX_EPOCH.elapsed().ok()?;
+    Duration::from_secs(claims.claims.exp).checked_sub(now).map(|dur| dur.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use super::*;
+
+    #[test]
+    fn test_id_token_expires_in() {
+        #[derive(serde::Serialize)]
+        struct Claims {
+            aud: &'static str,
+            exp: u64,
+        }
+
+        let exp = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() + 3600;
+        let token = encode(
+            &Header::default(),
+            &Claims { aud: "https://example.com", exp },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let expires_in = id_token_expires_in(&token).unwrap();
+        assert!(expires_in > 3590 && expires_in <= 3600);
+
+        let token = encode(
+            &Header::default(),
+            &Claims { aud: "https://example.com", exp: 1 },
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert_eq!(id_token_expires_in(&token), None);
+        assert_eq!(id_token_expires_in("invalid"), None);
+    }
+}
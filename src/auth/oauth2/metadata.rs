@@ -1,43 +1,186 @@
-use std::{fmt, str::FromStr as _};
+use std::{fmt, future::Future, str::FromStr as _, time::Duration};
 
-use futures_util::TryFutureExt as _;
-use hyper::{client::HttpConnector, http::uri::PathAndQuery, Body};
+use bytes::Bytes;
+use futures_util::{
+    future::{self, BoxFuture},
+    TryFutureExt as _,
+};
+use hyper::{
+    client::HttpConnector,
+    http::uri::{PathAndQuery, Scheme},
+    Body, Request, StatusCode, Uri,
+};
+use tracing::{debug, trace};
 
 use crate::{
-    auth::{self, oauth2::token},
+    auth::{
+        self,
+        oauth2::{
+            http::{self, HyperClient},
+            impersonated::id_token_expires_in,
+            token,
+        },
+        Config, ScopeDelimiter,
+    },
     credentials,
 };
 
+// https://cloud.google.com/compute/docs/access/authenticate-workloads#applications
+const SCOPE_DELIMITER: ScopeDelimiter = ScopeDelimiter::Comma;
+
+// The metadata server recovers from 500 and 503 within a moment, so retries back off briefly.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
 #[derive(serde::Serialize)]
 struct Query<'a> {
     scopes: &'a str,
 }
 
+#[derive(serde::Serialize)]
+struct IdentityQuery<'a> {
+    audience: &'a str,
+    format: &'a str,
+}
+
+// gcemeta reads a custom host only from `GCE_METADATA_HOST`, so requests are sent directly.
+#[derive(Clone)]
+pub(crate) struct Host {
+    client: HyperClient,
+    uri: Uri,
+    max_response_size: usize,
+}
+
+impl Host {
+    pub(crate) fn new(uri: Uri, config: &Config) -> Self {
+        let client = http::metadata_client(config);
+        Self { client, uri, max_response_size: config.max_response_size }
+    }
+
+    pub(crate) fn get(
+        &self,
+        path_and_query: PathAndQuery,
+    ) -> impl Future<Output = auth::Result<Bytes>> + Send + Sync + 'static {
+        let req = Request::get(metadata_uri(&self.uri, path_and_query))
+            .header("metadata-flavor", "Google")
+            .body(Body::empty())
+            .unwrap();
+        let fut = self.client.request(req);
+        let limit = self.max_response_size;
+        async move {
+            let (parts, body) = fut.await?.into_parts();
+            match parts.status {
+                StatusCode::OK => http::read_body(body, limit).await,
+                _ => Err(auth::Error::StatusCode((parts, body))),
+            }
+        }
+    }
+}
+
 pub struct Metadata {
     inner: gcemeta::Client<HttpConnector, Body>,
+    host: Option<Host>,
+    account: Option<String>,
     path_and_query: PathAndQuery,
+    timeout: Option<Duration>,
+    retry: u8,
 }
 
 impl Metadata {
-    pub(crate) fn new(meta: Box<credentials::Metadata>) -> Self {
-        let path_and_query = path_and_query(meta.account, meta.scopes);
+    pub(crate) fn new(meta: Box<credentials::Metadata>, config: &Config) -> Self {
+        let path_and_query = path_and_query(meta.account.as_deref(), &meta.scopes);
         let path_and_query = PathAndQuery::from_str(&path_and_query).unwrap();
-        Self { inner: meta.client, path_and_query }
+        let host = meta.host.map(|uri| Host::new(uri, config));
+        Self {
+            inner: meta.client,
+            host,
+            account: meta.account,
+            path_and_query,
+            timeout: meta.timeout,
+            retry: config.metadata_retry,
+        }
+    }
+
+    fn get(&self, path_and_query: PathAndQuery) -> BoxFuture<'static, auth::Result<Bytes>> {
+        let (inner, host, timeout) = (self.inner.clone(), self.host.clone(), self.timeout);
+        let mut retry = self.retry;
+        Box::pin(async move {
+            let mut backoff = RETRY_BACKOFF;
+            loop {
+                match request(&inner, host.as_ref(), path_and_query.clone(), timeout).await {
+                    Err(err) if retry > 0 && is_unavailable(&err) => {
+                        debug!("metadata server unavailable, retry in {:?}: {}", backoff, err);
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                        retry -= 1;
+                    }
+                    res => return res,
+                }
+            }
+        })
+    }
+}
+
+fn request(
+    inner: &gcemeta::Client<HttpConnector, Body>,
+    host: Option<&Host>,
+    path_and_query: PathAndQuery,
+    timeout: Option<Duration>,
+) -> BoxFuture<'static, auth::Result<Bytes>> {
+    let fut: BoxFuture<'static, auth::Result<Bytes>> = match host {
+        Some(host) => Box::pin(host.get(path_and_query)),
+        // Already checked that this process is running on GCE.
+        None => Box::pin(
+            inner.get(path_and_query, true).map_ok(Bytes::from).map_err(auth::Error::Gcemeta),
+        ),
+    };
+    match timeout {
+        Some(timeout) => Box::pin(async move {
+            tokio::time::timeout(timeout, fut).await.map_err(|_| auth::Error::Timeout)?
+        }),
+        None => fut,
     }
 }
 
-fn path_and_query(account: Option<String>, scopes: &'static [&'static str]) -> String {
+// Only the statuses the metadata server returns while it is busy or under maintenance.
+fn is_unavailable(err: &auth::Error) -> bool {
+    let status = match err {
+        auth::Error::Gcemeta(gcemeta::Error::StatusCode((parts, _))) => parts.status,
+        auth::Error::StatusCode((parts, _)) => parts.status,
+        _ => return false,
+    };
+    matches!(status, StatusCode::INTERNAL_SERVER_ERROR | StatusCode::SERVICE_UNAVAILABLE)
+}
+
+// The host defaults to the `http` scheme like `GCE_METADATA_HOST`.
+fn metadata_uri(host: &Uri, path_and_query: PathAndQuery) -> Uri {
+    let mut parts = host.clone().into_parts();
+    parts.scheme.get_or_insert(Scheme::HTTP);
+    parts.path_and_query = Some(path_and_query);
+    Uri::from_parts(parts).unwrap()
+}
+
+fn path_and_query(account: Option<&str>, scopes: &[String]) -> String {
     let mut path_and_query = "/computeMetadata/v1/instance/service-accounts/".to_owned();
-    path_and_query.push_str(account.as_ref().map_or("default", String::as_str));
+    path_and_query.push_str(account.unwrap_or("default"));
     path_and_query.push_str("/token");
     if !scopes.is_empty() {
         path_and_query.push('?');
-        let query = Query { scopes: &scopes.join(",") };
+        let query = Query { scopes: &SCOPE_DELIMITER.join(scopes) };
         path_and_query.push_str(&serde_urlencoded::to_string(&query).unwrap());
     }
     path_and_query
 }
 
+// https://cloud.google.com/compute/docs/instances/verifying-instance-identity#request_signature
+fn identity_path_and_query(account: Option<&str>, audience: &str) -> String {
+    let mut path_and_query = "/computeMetadata/v1/instance/service-accounts/".to_owned();
+    path_and_query.push_str(account.unwrap_or("default"));
+    path_and_query.push_str("/identity?");
+    let query = IdentityQuery { audience, format: "full" };
+    path_and_query.push_str(&serde_urlencoded::to_string(&query).unwrap());
+    path_and_query
+}
+
 impl fmt::Debug for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metadata").finish()
@@ -45,10 +188,40 @@ impl fmt::Debug for Metadata {
 }
 
 impl token::Fetcher for Metadata {
-    fn fetch(&self) -> token::ResponseFuture {
-        // Already checked that this process is running on GCE.
-        let fut = self.inner.get_as(self.path_and_query.clone()).map_err(auth::Error::Gcemeta);
-        Box::pin(fut)
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let path_and_query = match scopes {
+            Some(scopes) => {
+                let path_and_query = path_and_query(self.account.as_deref(), scopes);
+                PathAndQuery::from_str(&path_and_query).unwrap()
+            }
+            None => self.path_and_query.clone(),
+        };
+        // The scopes of the VM are used if the query has none.
+        trace!("fetch metadata token: scopes={:?}", path_and_query.query());
+        let fut = self.get(path_and_query);
+        Box::pin(async {
+            serde_json::from_slice(&fut.await?).map_err(auth::Error::JsonDeserialize)
+        })
+    }
+
+    fn fetch_id_token(&self, audience: &str) -> token::ResponseFuture {
+        let path_and_query = identity_path_and_query(self.account.as_deref(), audience);
+        let path_and_query = match PathAndQuery::from_str(&path_and_query) {
+            Ok(path_and_query) => path_and_query,
+            Err(err) => return Box::pin(future::ready(Err(gcemeta::Error::Uri(err).into()))),
+        };
+        let fut = self.get(path_and_query);
+        Box::pin(async {
+            // The identity endpoint returns the ID token as is.
+            let token = String::from_utf8_lossy(&fut.await?).trim().to_owned();
+            let expires_in = id_token_expires_in(&token).unwrap_or_default();
+            Ok(token::Response {
+                token_type: "Bearer".to_owned(),
+                access_token: token,
+                expires_in,
+                scope: None,
+            })
+        })
     }
 }
 
@@ -64,13 +237,50 @@ mod test {
         );
 
         assert_eq!(
-            &path_and_query(None, &["https://www.googleapis.com/auth/cloud-platform"]),
+            &path_and_query(None, &["https://www.googleapis.com/auth/cloud-platform".into()]),
             "/computeMetadata/v1/instance/service-accounts/default/token?scopes=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform"
         );
 
         assert_eq!(
-            &path_and_query(None, &["scope1", "scope2"]),
+            &path_and_query(None, &["scope1".into(), "scope2".into()]),
             "/computeMetadata/v1/instance/service-accounts/default/token?scopes=scope1%2Cscope2"
         );
     }
+
+    #[test]
+    fn test_identity_path_and_query() {
+        assert_eq!(
+            &identity_path_and_query(None, "https://example.com"),
+            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fexample.com&format=full"
+        );
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        let status = |status| {
+            let (parts, body) =
+                hyper::Response::builder().status(status).body(Body::empty()).unwrap().into_parts();
+            (parts, body)
+        };
+        let gcemeta = |s| auth::Error::Gcemeta(gcemeta::Error::StatusCode(status(s)));
+        assert!(is_unavailable(&gcemeta(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_unavailable(&gcemeta(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert!(!is_unavailable(&gcemeta(StatusCode::NOT_FOUND)));
+        assert!(is_unavailable(&auth::Error::StatusCode(status(StatusCode::SERVICE_UNAVAILABLE))));
+        assert!(!is_unavailable(&auth::Error::StatusCode(status(StatusCode::FORBIDDEN))));
+        assert!(!is_unavailable(&auth::Error::Timeout));
+    }
+
+    #[test]
+    fn test_metadata_uri() {
+        let path_and_query = || PathAndQuery::from_static("/computeMetadata/v1/");
+        assert_eq!(
+            metadata_uri(&Uri::from_static("127.0.0.1:8080"), path_and_query()),
+            "http://127.0.0.1:8080/computeMetadata/v1/"
+        );
+        assert_eq!(
+            metadata_uri(&Uri::from_static("https://metadata-proxy"), path_and_query()),
+            "https://metadata-proxy/computeMetadata/v1/"
+        );
+    }
 }
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use futures_util::future;
+
+use crate::auth::oauth2::token;
+
+/// A [`Fetcher`](token::Fetcher) that always returns the given token, for testing without
+/// reaching Google.
+///
+/// ```
+/// use std::{convert::Infallible, future, task};
+///
+/// use google_authz::{Credentials, GoogleAuthz, MockFetcher};
+/// use hyper::{header::AUTHORIZATION, Request};
+///
+/// #[derive(Clone)]
+/// struct Echo;
+///
+/// impl tower_service::Service<Request<()>> for Echo {
+///     type Response = Request<()>;
+///     type Error = Infallible;
+///     type Future = future::Ready<Result<Self::Response, Self::Error>>;
+///
+///     fn poll_ready(&mut self, _: &mut task::Context<'_>) -> task::Poll<Result<(), Infallible>> {
+///         task::Poll::Ready(Ok(()))
+///     }
+///
+///     fn call(&mut self, req: Request<()>) -> Self::Future {
+///         future::ready(Ok(req))
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use tower_service::Service as _;
+///
+/// let fetcher = MockFetcher::new("token", std::time::Duration::from_secs(3600));
+/// let credentials = Credentials::builder().fetcher(fetcher).build().await.unwrap();
+/// let mut service = GoogleAuthz::builder(Echo).credentials(credentials).build().await;
+///
+/// future::poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+/// let req = service.call(Request::get("https://example.com").body(()).unwrap()).await.unwrap();
+/// assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockFetcher {
+    access_token: String,
+    expires_in: Duration,
+}
+
+impl MockFetcher {
+    pub fn new(access_token: impl Into<String>, expires_in: Duration) -> Self {
+        Self { access_token: access_token.into(), expires_in }
+    }
+}
+
+impl token::Fetcher for MockFetcher {
+    fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+        Box::pin(future::ready(Ok(token::Response {
+            token_type: "Bearer".to_owned(),
+            access_token: self.access_token.clone(),
+            expires_in: self.expires_in.as_secs(),
+            scope: None,
+        })))
+    }
+
+    // The same token is returned for any audience.
+    fn fetch_id_token(&self, _: &str) -> token::ResponseFuture {
+        self.fetch(None)
+    }
+}
@@ -1,143 +1,409 @@
 use std::{
+    collections::HashMap,
     convert::TryFrom as _,
     fmt,
-    sync::Arc,
+    future::Future as _,
+    pin::Pin,
+    sync::{Arc, Weak},
     task::{self, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+use arc_swap::ArcSwapOption;
+use futures_util::{
+    future::{self, BoxFuture, Shared},
+    ready, FutureExt as _,
+};
 use hyper::{
     header::{self, AUTHORIZATION},
     Request,
 };
-use parking_lot::RwLock;
-use tracing::{info, trace};
+use parking_lot::{Mutex, RwLock};
+use tracing::{debug, error, info, trace, warn, Level};
 
-use crate::{auth, sync::RefGuard};
+use crate::auth::{self, Config, Metrics, RefreshThreshold};
 
 mod http;
 pub(super) mod token;
 
+mod client_credentials;
+mod gcloud;
+mod granted;
+mod impersonated;
 mod metadata;
+mod mock;
+mod secret_manager;
 mod service_account;
+mod token_exchange;
 mod user;
+mod watched;
 
+pub use client_credentials::ClientCredentials;
+pub use gcloud::GcloudCli;
+pub use granted::GrantedScopes;
+#[cfg(not(feature = "openssl"))]
+pub use http::tls_roots;
+pub use impersonated::Impersonated;
+pub(crate) use metadata::Host as MetadataHost;
 pub use metadata::Metadata;
+pub use mock::MockFetcher;
+pub(crate) use secret_manager::access_secret;
+pub(crate) use service_account::check_extra_claims;
 pub use service_account::ServiceAccount;
+pub use token_exchange::TokenExchange;
 pub use user::User;
+pub(crate) use user::{fetch_email as fetch_user_email, USERINFO_EMAIL_SCOPE};
+pub use watched::Watched;
+
+// Fails with `Error::Timeout` if `future` does not complete within `timeout`.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl std::future::Future<Output = auth::Result<T>>,
+) -> auth::Result<T> {
+    match timeout {
+        Some(duration) => {
+            tokio::time::timeout(duration, future).await.unwrap_or(Err(auth::Error::Timeout))
+        }
+        None => future.await,
+    }
+}
+
+// The fetch shared by all the tasks waiting for a token. It updates the cache when it completes.
+type SharedFetch = Shared<BoxFuture<'static, Result<(), Arc<auth::Error>>>>;
 
-#[derive(Clone)]
 pub(super) struct Oauth2 {
-    inner: Arc<RwLock<Inner>>,
+    cache: Arc<Cache>,
+    // Tokens for the scopes that override the ones of the credentials, keyed by scope string.
+    scoped: Arc<Mutex<HashMap<String, Arc<Cache>>>>,
+    // ID tokens keyed by audience.
+    audiences: Arc<Mutex<Audiences>>,
+    // The in-flight fetch this handle waits for. It is kept across polls so that the waker of
+    // this handle stays registered.
+    pending: Option<SharedFetch>,
 }
 
 impl Oauth2 {
-    pub fn new(fetcher: Box<dyn token::Fetcher>, max_retry: u8) -> Self {
+    pub fn new(fetcher: Box<dyn token::Fetcher>, config: &Config) -> Self {
+        let inner = Inner {
+            state: State::Idle,
+            fetcher: fetcher.into(),
+            target: Target::Default,
+            max_retry: config.max_retry,
+            failure_level: config.fetch_failure_level,
+            fetch_timeout: config.fetch_timeout,
+            min_refresh_interval: config.min_refresh_interval,
+            last_fetch: None,
+            expiry_jitter: config.expiry_jitter,
+            rng: fastrand::Rng::new(),
+            metrics: config.metrics.clone(),
+        };
+        let cache = Cache::new(inner, config.refresh_threshold, config.serve_stale);
         Self {
-            inner: Arc::new(RwLock::new(Inner { state: State::NotFetched, fetcher, max_retry })),
+            cache: Arc::new(cache),
+            scoped: Default::default(),
+            audiences: Default::default(),
+            pending: None,
         }
     }
 
+    /// Returns an `Oauth2` that fetches tokens for `scopes` using the same credentials.
+    /// Tokens are cached per distinct scope set and shared among the derived values.
+    pub fn with_scopes(&self, scopes: Vec<String>) -> Self {
+        let cache = self
+            .scoped
+            .lock()
+            .entry(scopes.join(" "))
+            .or_insert_with(|| self.cache.derive(Target::Scopes(scopes)))
+            .clone();
+        self.with_cache(cache)
+    }
+
+    /// Returns an `Oauth2` that fetches ID tokens for `audience` using the same credentials.
+    /// Tokens are cached per audience, up to the most recently used `MAX_AUDIENCES` ones.
+    pub fn with_audience(&self, audience: String) -> Self {
+        let cache = self
+            .audiences
+            .lock()
+            .get_or_insert_with(audience, |audience| self.cache.derive(Target::Audience(audience)));
+        self.with_cache(cache)
+    }
+
+    fn with_cache(&self, cache: Arc<Cache>) -> Self {
+        Self {
+            cache,
+            scoped: self.scoped.clone(),
+            audiences: self.audiences.clone(),
+            pending: None,
+        }
+    }
+
+    /// Waits for a valid token. Concurrent callers share a single fetch, and a valid token is
+    /// checked without locking.
     pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
-        if self.inner.read().can_skip_poll_ready() {
-            return Poll::Ready(Ok(()));
+        loop {
+            if let Some(ref mut pending) = self.pending {
+                let res = ready!(Pin::new(pending).poll(cx));
+                self.pending = None;
+                if let Err(ref err) = res {
+                    if self.cache.is_stale_usable() {
+                        warn!("failed to refresh token, serve the last one: err={:?}", err);
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                return Poll::Ready(res.map_err(auth::Error::shared));
+            }
+            if self.cache.is_valid() {
+                return Poll::Ready(Ok(()));
+            }
+            self.pending = Some(Cache::start_fetch(&self.cache));
         }
-        self.inner.write().poll_ready(cx)
+    }
+
+    #[inline]
+    pub fn value(&self) -> header::HeaderValue {
+        match *self.cache.token.load() {
+            Some(ref token) => token.value.clone(),
+            None => unreachable!("token is not fetched"),
+        }
+    }
+
+    pub fn access_token(&self) -> token::AccessToken {
+        match *self.cache.token.load() {
+            Some(ref token) => token.token.clone(),
+            None => unreachable!("token is not fetched"),
+        }
+    }
+
+    /// Returns the `Authorization` header value of the cached token, if any.
+    pub fn cached_value(&self) -> Option<header::HeaderValue> {
+        self.cache.token.load().as_ref().map(|token| token.value.clone())
+    }
+
+    /// Marks the current token as expired so that the next `poll_ready` fetches a new one.
+    /// The current token is still used until then, like when it expires normally.
+    pub fn invalidate(&self) {
+        self.invalidate_if(|_| true);
+    }
+
+    /// Same as [`invalidate`](Self::invalidate), but only if the current token has `value`, so
+    /// that a token fetched after a request was sent is kept.
+    pub fn invalidate_value(&self, value: &header::HeaderValue) {
+        self.invalidate_if(|token| token.value == value);
+    }
+
+    fn invalidate_if(&self, f: impl Fn(&token::Token) -> bool) {
+        let now = Instant::now();
+        self.cache.token.rcu(|token| match token {
+            Some(token) if f(token) => {
+                trace!("invalidate token: expiry={:?}", token.expiry);
+                Some(Arc::new(token::Token { expiry: now, ..token::Token::clone(token) }))
+            }
+            token => token.clone(),
+        });
+    }
+
+    /// Reports whether a token is cached and `poll_ready` would not refresh it.
+    pub fn is_valid(&self) -> bool {
+        self.cache.is_valid()
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.cache.token.load().as_ref().map(|token| token.expiry.saturating_duration_since(now))
     }
 
     #[inline]
     pub fn add_header<B>(&self, mut req: Request<B>) -> Request<B> {
-        req.headers_mut().insert(AUTHORIZATION, self.inner.read().value());
+        req.headers_mut().insert(AUTHORIZATION, self.value());
         req
     }
 }
 
+impl Clone for Oauth2 {
+    fn clone(&self) -> Self {
+        self.with_cache(self.cache.clone())
+    }
+}
+
 impl fmt::Debug for Oauth2 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Oauth2").field("inner", &self.inner).finish()
+        f.debug_struct("Oauth2").field("cache", &self.cache).finish()
+    }
+}
+
+struct Cache {
+    // The last fetched token. It is kept after it expires until a new one is fetched.
+    token: ArcSwapOption<token::Token>,
+    refresh_threshold: RefreshThreshold,
+    // How long after it expires the last token is served when refreshing it fails.
+    serve_stale: Option<Duration>,
+    // Guards only the fetch state, so that reading the token never takes the lock.
+    inner: RwLock<Inner>,
+}
+
+impl Cache {
+    fn new(
+        inner: Inner,
+        refresh_threshold: RefreshThreshold,
+        serve_stale: Option<Duration>,
+    ) -> Self {
+        Self {
+            token: ArcSwapOption::empty(),
+            refresh_threshold,
+            serve_stale,
+            inner: RwLock::new(inner),
+        }
+    }
+
+    // Returns a cache of tokens for `target` using the same credentials and configuration.
+    fn derive(&self, target: Target) -> Arc<Self> {
+        let inner = self.inner.read().with_target(target);
+        Arc::new(Self::new(inner, self.refresh_threshold, self.serve_stale))
+    }
+
+    #[inline]
+    fn is_valid(&self) -> bool {
+        matches!(
+            *self.token.load(),
+            Some(ref token) if !token.expired(Instant::now(), self.refresh_threshold)
+        )
+    }
+
+    // Reports whether the last token can be served after refreshing it failed.
+    fn is_stale_usable(&self) -> bool {
+        match (self.serve_stale, &*self.token.load()) {
+            (Some(grace), Some(token)) => Instant::now() < token.expiry + grace,
+            _ => false,
+        }
+    }
+
+    // Returns the in-flight fetch, or starts a new one if the token is missing or expired.
+    // At most one fetch exists per cache: it is created and stored under the write lock, and the
+    // waiters are woken by the shared future when it completes, so no poller creates another one.
+    fn start_fetch(this: &Arc<Self>) -> SharedFetch {
+        let mut inner = this.inner.write();
+        if let State::Fetching { ref future } = inner.state {
+            return future.clone();
+        }
+        // The token may have been fetched while waiting for the lock.
+        if this.is_valid() {
+            return future::ready(Ok(())).boxed().shared();
+        }
+
+        if let (Some(interval), Some(last_fetch)) = (inner.min_refresh_interval, inner.last_fetch) {
+            let elapsed = last_fetch.elapsed();
+            if elapsed < interval {
+                let err = auth::Error::RefreshRateLimited(interval - elapsed);
+                return future::ready(Err(Arc::new(err))).boxed().shared();
+            }
+        }
+
+        match *this.token.load() {
+            Some(ref token) => trace!("token will expire: expiry={:?}", token.expiry),
+            None => trace!("token is not fetched"),
+        }
+        inner.last_fetch = Some(Instant::now());
+        let future = inner.fetch(Arc::downgrade(this));
+        inner.state = State::Fetching { future: future.clone() };
+        future
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("refresh_threshold", &self.refresh_threshold)
+            .field("serve_stale", &self.serve_stale)
+            .field("inner", &self.inner)
+            .finish()
     }
 }
 
 struct Inner {
     state: State,
-    fetcher: Box<dyn token::Fetcher>,
+    fetcher: Arc<dyn token::Fetcher>,
+    target: Target,
     max_retry: u8,
+    // The level of the failure that ends a fetch. Failures that are retried log at info.
+    failure_level: Level,
+    fetch_timeout: Option<Duration>,
+    min_refresh_interval: Option<Duration>,
+    // When the last fetch started.
+    last_fetch: Option<Instant>,
+    expiry_jitter: Duration,
+    // Seeded per instance, so that instances started at the same time refresh at different times.
+    rng: fastrand::Rng,
+    metrics: Arc<dyn Metrics>,
 }
 
 impl Inner {
-    #[inline]
-    fn can_skip_poll_ready(&self) -> bool {
-        matches!(self.state, State::Fetched { ref current } if !current.expired(Instant::now()))
+    fn with_target(&self, target: Target) -> Self {
+        Self {
+            state: State::Idle,
+            fetcher: self.fetcher.clone(),
+            target,
+            max_retry: self.max_retry,
+            failure_level: self.failure_level,
+            fetch_timeout: self.fetch_timeout,
+            min_refresh_interval: self.min_refresh_interval,
+            last_fetch: None,
+            expiry_jitter: self.expiry_jitter,
+            rng: fastrand::Rng::new(),
+            metrics: self.metrics.clone(),
+        }
     }
 
-    #[inline]
-    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
-        macro_rules! poll {
-            ($variant:ident, $future:expr, $attempts:ident) => {
-                poll!($variant, $future, $attempts,)
-            };
-            ($variant:ident, $future:expr, $attempts:ident, $($field:ident),*) => {
-                match $future.get_mut().as_mut().poll(cx) {
-                    Poll::Ready(resp) => match resp.and_then(token::Token::try_from) {
-                        Ok(token) => {
-                            trace!("fetched token: expiry={:?}", token.expiry);
-                            self.state = State::Fetched { current: token };
-                            break Poll::Ready(Ok(()));
-                        }
-                        Err(err) => {
-                            if $attempts > self.max_retry {
-                                break Poll::Ready(Err(err));
-                            }
-                            info!("an error occurred during token fetching: attempts={}, err={:?}", $attempts, err);
-                            self.state = State::$variant {
-                                future: RefGuard::new(self.fetcher.fetch()),
-                                attempts: $attempts + 1,
-                                $(
-                                    $field: $field.clone(),
-                                )*
-                            };
+    fn fetch(&mut self, cache: Weak<Cache>) -> SharedFetch {
+        let jitter = self.expiry_jitter.mul_f64(self.rng.f64());
+        let fetcher = self.fetcher.clone();
+        let target = self.target.clone();
+        let max_retry = self.max_retry;
+        let failure_level = self.failure_level;
+        let fetch_timeout = self.fetch_timeout;
+        let metrics = self.metrics.clone();
+        let future = async move {
+            let mut attempts = 1;
+            let res = loop {
+                metrics.fetch_attempt();
+                let future = match target {
+                    Target::Default => fetcher.fetch(None),
+                    Target::Scopes(ref scopes) => fetcher.fetch(Some(scopes)),
+                    Target::Audience(ref audience) => fetcher.fetch_id_token(audience),
+                };
+                let resp = with_timeout(fetch_timeout, future).await;
+                match resp.and_then(token::Token::try_from) {
+                    Ok(token) => {
+                        let token = token.with_jitter(jitter);
+                        metrics.fetch_success();
+                        trace!("fetched token: expiry={:?}", token.expiry);
+                        break Ok(token);
+                    }
+                    Err(err) => {
+                        metrics.fetch_failure(&err);
+                        if attempts > max_retry || !err.is_retryable() {
+                            log_failure(failure_level, attempts, &err);
+                            break Err(err);
                         }
-                    },
-                    Poll::Pending => break Poll::Pending,
+                        info!(
+                            "an error occurred during token fetching: attempts={}, err={:?}",
+                            attempts, err
+                        );
+                        metrics.fetch_retry();
+                        attempts += 1;
+                    }
                 }
             };
-        }
 
-        loop {
-            match self.state {
-                State::NotFetched => {
-                    trace!("token is not fetched");
-                    self.state = State::Fetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
-                        attempts: 1,
-                    };
-                }
-                State::Fetching { ref mut future, attempts } => poll!(Fetching, future, attempts),
-                State::Refetching { ref mut future, attempts, ref last } => {
-                    poll!(Refetching, future, attempts, last)
-                }
-                State::Fetched { ref current } => {
-                    if !current.expired(Instant::now()) {
-                        break Poll::Ready(Ok(()));
-                    }
-                    trace!("token will expire: expiry={:?}", current.expiry);
-                    self.state = State::Refetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
-                        attempts: 1,
-                        last: current.clone(),
-                    };
+            if let Some(cache) = cache.upgrade() {
+                // On failure, the last token is kept and used until it expires.
+                if let Ok(ref token) = res {
+                    cache.token.store(Some(Arc::new(token.clone())));
                 }
+                cache.inner.write().state = State::Idle;
             }
-        }
-    }
-
-    #[inline]
-    fn value(&self) -> header::HeaderValue {
-        match self.state {
-            State::Fetched { ref current } => current.value.clone(),
-            State::Refetching { ref last, .. } => last.value.clone(),
-            _ => unreachable!("invalid state: {:?}", self.state),
-        }
+            res.map(|_| ()).map_err(Arc::new)
+        };
+        future.boxed().shared()
     }
 }
 
@@ -146,25 +412,395 @@ impl fmt::Debug for Inner {
         f.debug_struct("Inner")
             .field("state", &self.state)
             .field("fetcher", &self.fetcher)
+            .field("target", &self.target)
             .field("max_retry", &self.max_retry)
+            .field("failure_level", &self.failure_level)
+            .field("fetch_timeout", &self.fetch_timeout)
+            .field("min_refresh_interval", &self.min_refresh_interval)
+            .field("expiry_jitter", &self.expiry_jitter)
+            .field("metrics", &self.metrics)
             .finish()
     }
 }
 
+fn log_failure(level: Level, attempts: u8, err: &auth::Error) {
+    match level {
+        Level::ERROR => error!("failed to fetch token: attempts={}, err={:?}", attempts, err),
+        Level::WARN => warn!("failed to fetch token: attempts={}, err={:?}", attempts, err),
+        Level::INFO => info!("failed to fetch token: attempts={}, err={:?}", attempts, err),
+        Level::DEBUG => {
+            debug!("failed to fetch token: attempts={}, err={:?}", attempts, err)
+        }
+        Level::TRACE => trace!("failed to fetch token: attempts={}, err={:?}", attempts, err),
+    }
+}
+
+// What tokens are fetched for.
+#[derive(Clone, Debug)]
+enum Target {
+    // Access tokens for the scopes of the credentials.
+    Default,
+    // Access tokens for the scopes that override the ones of the credentials.
+    Scopes(Vec<String>),
+    // ID tokens for the audience.
+    Audience(String),
+}
+
+const MAX_AUDIENCES: usize = 32;
+
+// A small LRU cache of token caches keyed by audience.
+#[derive(Default)]
+struct Audiences {
+    caches: HashMap<String, (Arc<Cache>, u64)>,
+    clock: u64,
+}
+
+impl Audiences {
+    fn get_or_insert_with(
+        &mut self,
+        audience: String,
+        f: impl FnOnce(String) -> Arc<Cache>,
+    ) -> Arc<Cache> {
+        self.clock += 1;
+        if let Some((cache, used)) = self.caches.get_mut(&audience) {
+            *used = self.clock;
+            return cache.clone();
+        }
+        if self.caches.len() >= MAX_AUDIENCES {
+            // Handles that still use the evicted cache keep it alive.
+            let lru = self.caches.iter().min_by_key(|(_, (_, used))| *used).map(|(k, _)| k.clone());
+            if let Some(lru) = lru {
+                trace!("evict id token cache: audience={}", lru);
+                self.caches.remove(&lru);
+            }
+        }
+        let cache = f(audience.clone());
+        self.caches.insert(audience, (cache.clone(), self.clock));
+        cache
+    }
+}
+
 enum State {
-    NotFetched,
-    Fetching { future: RefGuard<token::ResponseFuture>, attempts: u8 },
-    Refetching { future: RefGuard<token::ResponseFuture>, attempts: u8, last: token::Token },
-    Fetched { current: token::Token },
+    Idle,
+    Fetching { future: SharedFetch },
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::NotFetched => write!(f, "NotFetched"),
+            Self::Idle => write!(f, "Idle"),
             Self::Fetching { .. } => write!(f, "Fetching"),
-            Self::Refetching { .. } => write!(f, "Refetching"),
-            Self::Fetched { .. } => write!(f, "Fetched"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::future;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FailOnce(AtomicUsize);
+
+    impl token::Fetcher for FailOnce {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            let resp = match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(auth::Error::TokenFormat(token::Response {
+                    token_type: String::new(),
+                    access_token: String::new(),
+                    expires_in: 0,
+                    scope: None,
+                })),
+                _ => Ok(token::Response {
+                    token_type: "Bearer".into(),
+                    access_token: "token".into(),
+                    expires_in: 3600,
+                    scope: None,
+                }),
+            };
+            Box::pin(future::ready(resp))
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Counters {
+        attempt: AtomicUsize,
+        success: AtomicUsize,
+        failure: AtomicUsize,
+        retry: AtomicUsize,
+    }
+
+    impl Metrics for Arc<Counters> {
+        fn fetch_attempt(&self) {
+            self.attempt.fetch_add(1, Ordering::SeqCst);
+        }
+        fn fetch_success(&self) {
+            self.success.fetch_add(1, Ordering::SeqCst);
+        }
+        fn fetch_failure(&self, _: &auth::Error) {
+            self.failure.fetch_add(1, Ordering::SeqCst);
+        }
+        fn fetch_retry(&self) {
+            self.retry.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let counters = Arc::new(Counters::default());
+        let config = Config { metrics: Arc::new(counters.clone()), ..Default::default() };
+        let mut oauth2 = Oauth2::new(Box::new(FailOnce(AtomicUsize::new(0))), &config);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+
+        assert_eq!(counters.attempt.load(Ordering::SeqCst), 2);
+        assert_eq!(counters.success.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.failure.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.retry.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Debug)]
+    struct Hang;
+
+    impl token::Fetcher for Hang {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            Box::pin(future::pending())
+        }
+    }
+
+    #[derive(Debug)]
+    struct InvalidGrant;
+
+    impl token::Fetcher for InvalidGrant {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Err(auth::Error::InvalidGrant(Some("revoked".into())))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_error() {
+        let mut oauth2 = Oauth2::new(Box::new(InvalidGrant), &Config::default());
+        let mut clone = oauth2.clone();
+        let (res1, res2) = future::join(
+            future::poll_fn(|cx| oauth2.poll_ready(cx)),
+            future::poll_fn(|cx| clone.poll_ready(cx)),
+        )
+        .await;
+        for res in [res1, res2] {
+            let err = res.unwrap_err();
+            assert!(matches!(err, auth::Error::InvalidGrant(Some(ref d)) if d == "revoked"));
+            assert!(!err.is_retryable());
+        }
+
+        let err = auth::Error::shared(Arc::new(auth::Error::GcloudCommand(
+            std::io::ErrorKind::NotFound.into(),
+        )));
+        assert!(matches!(err, auth::Error::Shared(_)));
+        assert!(matches!(err.inner(), auth::Error::GcloudCommand(_)));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_timeout() {
+        let config = Config {
+            max_retry: 0,
+            fetch_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut oauth2 = Oauth2::new(Box::new(Hang), &config);
+        let res = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert!(matches!(res, Err(auth::Error::Timeout)));
+    }
+
+    #[derive(Debug)]
+    struct FailAfterFirst(AtomicUsize);
+
+    impl token::Fetcher for FailAfterFirst {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            let resp = match self.0.fetch_add(1, Ordering::SeqCst) {
+                // Refreshed on every poll, since it expires within the refresh threshold.
+                0 => Ok(token::Response {
+                    token_type: "Bearer".into(),
+                    access_token: "token".into(),
+                    expires_in: 5,
+                    scope: None,
+                }),
+                _ => Err(auth::Error::IdTokenUnsupported),
+            };
+            Box::pin(future::ready(resp))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale() {
+        let config = Config { max_retry: 0, serve_stale: None, ..Default::default() };
+        let mut oauth2 = Oauth2::new(Box::new(FailAfterFirst(AtomicUsize::new(0))), &config);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        let res = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert!(matches!(res, Err(auth::Error::IdTokenUnsupported)));
+
+        // By default, the token is served until it expires.
+        let config = Config { max_retry: 0, ..Default::default() };
+        let mut oauth2 = Oauth2::new(Box::new(FailAfterFirst(AtomicUsize::new(0))), &config);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(oauth2.value(), "Bearer token");
+
+        // The token is no longer served after it expires.
+        oauth2.invalidate();
+        let res = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert!(matches!(res, Err(auth::Error::IdTokenUnsupported)));
+    }
+
+    #[tokio::test]
+    async fn test_min_refresh_interval() {
+        let config = Config {
+            serve_stale: None,
+            min_refresh_interval: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let fetcher = FailAfterFirst(AtomicUsize::new(0));
+        let mut oauth2 = Oauth2::new(Box::new(fetcher), &config);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        // The token looks expired, but it is not fetched again.
+        let res = future::poll_fn(|cx| oauth2.poll_ready(cx)).await;
+        assert!(matches!(res, Err(auth::Error::RefreshRateLimited(_))));
+    }
+
+    #[derive(Debug)]
+    struct Echo;
+
+    impl token::Fetcher for Echo {
+        fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+            Box::pin(future::ready(Ok(token::Response {
+                token_type: "Bearer".into(),
+                access_token: scopes.map_or("default".into(), |scopes| scopes.join("+")),
+                expires_in: 3600,
+                scope: None,
+            })))
+        }
+
+        fn fetch_id_token(&self, audience: &str) -> token::ResponseFuture {
+            Box::pin(future::ready(Ok(token::Response {
+                token_type: "Bearer".into(),
+                access_token: format!("id-{}", audience),
+                expires_in: 3600,
+                scope: None,
+            })))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_scopes() {
+        async fn value(mut oauth2: Oauth2) -> header::HeaderValue {
+            future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+            let req = oauth2.add_header(Request::new(()));
+            req.headers()[AUTHORIZATION].clone()
+        }
+
+        let oauth2 = Oauth2::new(Box::new(Echo), &Config::default());
+        let scopes = |scopes: &[&str]| scopes.iter().map(|s| s.to_string()).collect();
+        let a = oauth2.with_scopes(scopes(&["a"]));
+        let b = oauth2.with_scopes(scopes(&["a", "b"]));
+        assert_eq!(value(oauth2.clone()).await, "Bearer default");
+        assert_eq!(value(a.clone()).await, "Bearer a");
+        assert_eq!(value(b).await, "Bearer a+b");
+        assert!(Arc::ptr_eq(&a.cache, &oauth2.with_scopes(scopes(&["a"])).cache));
+    }
+
+    #[tokio::test]
+    async fn test_with_audience() {
+        let oauth2 = Oauth2::new(Box::new(Echo), &Config::default());
+        let mut a = oauth2.with_audience("a".into());
+        future::poll_fn(|cx| a.poll_ready(cx)).await.unwrap();
+        assert_eq!(a.value(), "Bearer id-a");
+        assert!(Arc::ptr_eq(&a.cache, &oauth2.with_audience("a".into()).cache));
+
+        // The least recently used audience is evicted.
+        for i in 0..MAX_AUDIENCES {
+            oauth2.with_audience(i.to_string());
+            oauth2.with_audience("a".into());
+        }
+        assert!(Arc::ptr_eq(&a.cache, &oauth2.with_audience("a".into()).cache));
+        assert_eq!(oauth2.audiences.lock().caches.len(), MAX_AUDIENCES);
+        assert!(!oauth2.audiences.lock().caches.contains_key("0"));
+    }
+
+    #[derive(Debug)]
+    struct Slow(Arc<AtomicUsize>);
+
+    impl token::Fetcher for Slow {
+        fn fetch(&self, _: Option<&[String]>) -> token::ResponseFuture {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok(token::Response {
+                    token_type: "Bearer".into(),
+                    access_token: "token".into(),
+                    expires_in: 3600,
+                    scope: None,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let oauth2 = Oauth2::new(Box::new(Slow(fetches.clone())), &Config::default());
+        let results = future::join_all((0..10).map(|_| {
+            let mut oauth2 = oauth2.clone();
+            async move { future::poll_fn(|cx| oauth2.poll_ready(cx)).await }
+        }))
+        .await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(oauth2.value(), "Bearer token");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_single_flight_cold_start() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let oauth2 = Oauth2::new(Box::new(Slow(fetches.clone())), &Config::default());
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let mut oauth2 = oauth2.clone();
+                tokio::spawn(async move { future::poll_fn(|cx| oauth2.poll_ready(cx)).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(matches!(oauth2.cache.inner.read().state, State::Idle));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let counters = Arc::new(Counters::default());
+        let config = Config { metrics: Arc::new(counters.clone()), ..Default::default() };
+        let mut oauth2 = Oauth2::new(Box::new(Echo), &config);
+        oauth2.invalidate();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(counters.success.load(Ordering::SeqCst), 1);
+
+        oauth2.invalidate();
+        assert_eq!(oauth2.ttl(), Some(Duration::ZERO));
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(counters.success.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let mut oauth2 = Oauth2::new(Box::new(Echo), &Config::default());
+        assert_eq!(oauth2.ttl(), None);
+        future::poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        let ttl = oauth2.ttl().unwrap();
+        assert!(ttl > Duration::from_secs(3590) && ttl <= Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,53 @@
+use std::panic;
+
+use hyper::{header::HeaderValue, Uri};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, with_timeout},
+        Config,
+    },
+    credentials::Credentials,
+};
+
+// https://cloud.google.com/secret-manager/docs/reference/rest/v1/projects.secrets.versions/access
+const SECRET_MANAGER_URI: &str = "https://secretmanager.googleapis.com/v1";
+
+#[derive(serde::Deserialize)]
+struct AccessResponse {
+    payload: SecretPayload,
+}
+
+#[derive(serde::Deserialize)]
+struct SecretPayload {
+    // Base64-encoded.
+    data: String,
+}
+
+/// Accesses the secret version `name`, e.g. `projects/p/secrets/s/versions/latest`, with a token
+/// of `credentials`, and returns its base64-encoded payload. `name` must be a valid URI path.
+/// `config.fetch_timeout` bounds both the token fetch and the access.
+pub(crate) async fn access_secret(
+    credentials: Credentials,
+    name: &str,
+    config: &Config,
+) -> auth::Result<String> {
+    let fetcher = auth::plain_fetcher(credentials, config);
+    let client = Client::new(config);
+    let uri: Uri = format!("{}/{}:access", SECRET_MANAGER_URI, name).parse().unwrap();
+    let access = with_timeout(config.fetch_timeout, async move {
+        let resp = fetcher.fetch(None).await?;
+        let value = format!("{} {}", resp.token_type, resp.access_token);
+        let authorization =
+            HeaderValue::from_str(&value).map_err(|_| auth::Error::TokenFormat(resp))?;
+        let resp: AccessResponse = client.send(client.get(&uri, authorization)).await?;
+        Ok(resp.payload.data)
+    });
+    // Token futures are not `Sync`, but the future of `credentials::Builder::build` has to be, so
+    // the access runs on its own task.
+    match tokio::spawn(access).await {
+        Ok(res) => res,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
@@ -1,17 +1,51 @@
-use std::{fmt, time::SystemTime};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
+use futures_util::future;
 use hyper::Uri;
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::{Map, Value};
+use tracing::{trace, warn};
 
 use crate::{
-    auth::oauth2::{http::Client, token},
+    auth::{
+        self,
+        oauth2::{http::Client, token},
+        Config, ScopeDelimiter,
+    },
     credentials,
 };
 
+// https://developers.google.com/identity/protocols/oauth2/service-account#jwt-auth
+const SCOPE_DELIMITER: ScopeDelimiter = ScopeDelimiter::Space;
+
 // If client machine's time is in the future according
 // to Google servers, an access token will not be issued.
-fn issued_at() -> u64 {
-    SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() - 10
+const IAT_MARGIN: u64 = 10;
+
+fn issued_at(offset: i64) -> u64 {
+    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+    (now + offset - IAT_MARGIN as i64) as u64
+}
+
+// The offset in seconds to add to the local clock to match the server clock.
+fn offset_between(server: SystemTime, local: SystemTime) -> i64 {
+    match server.duration_since(local) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    }
+}
+
+// e.g. "Invalid JWT: Token must be a short-lived token (60 minutes) and in a reasonable timeframe.
+// Check your iat and exp values in the JWT claim."
+fn is_clock_skew(err: &auth::Error) -> bool {
+    matches!(err, auth::Error::InvalidGrant(Some(desc)) if desc.contains("iat") || desc.contains("timeframe"))
 }
 
 // https://cloud.google.com/iot/docs/concepts/device-security#security_standards
@@ -24,13 +58,46 @@ fn header(typ: impl Into<String>, key_id: impl Into<String>) -> Header {
     }
 }
 
+// A single audience is a string, several are an array.
+// https://datatracker.ietf.org/doc/html/rfc7519#section-4.1.3
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum Audience<'a> {
+    One(&'a str),
+    Many(&'a [String]),
+}
+
+impl<'a> From<&'a [String]> for Audience<'a> {
+    fn from(audiences: &'a [String]) -> Self {
+        match audiences {
+            [audience] => Self::One(audience),
+            audiences => Self::Many(audiences),
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 struct Claims<'a> {
     iss: &'a str,
-    scope: &'a str,
-    aud: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    aud: Audience<'a>,
     iat: u64,
     exp: u64,
+    #[serde(flatten)]
+    extra: &'a Map<String, Value>,
+}
+
+const RESERVED_CLAIMS: [&str; 6] = ["iss", "sub", "scope", "aud", "iat", "exp"];
+
+/// Checks that `claims` do not override the claims set by this crate.
+pub(crate) fn check_extra_claims(claims: &Map<String, Value>) -> auth::Result<()> {
+    match claims.keys().find(|key| RESERVED_CLAIMS.contains(&key.as_str())) {
+        Some(key) => Err(auth::Error::ReservedClaim(key.clone())),
+        None => Ok(()),
+    }
 }
 
 #[derive(serde::Serialize)]
@@ -48,18 +115,28 @@ pub struct ServiceAccount {
     token_uri_str: String,
     scopes: String,
     client_email: String,
+    lifetime: u64,
+    extra_claims: Map<String, Value>,
+    // Not empty only when self-signed JWTs are used as tokens.
+    audiences: Vec<String>,
+    // Present only when clock synchronization is enabled.
+    clock_offset: Option<Arc<AtomicI64>>,
 }
 
 impl ServiceAccount {
-    pub(crate) fn new(sa: credentials::ServiceAccount) -> Self {
+    pub(crate) fn new(sa: credentials::ServiceAccount, config: &Config) -> Self {
         Self {
-            inner: Client::new(),
+            inner: Client::new(config),
             header: header("JWT", sa.private_key_id),
-            private_key: EncodingKey::from_rsa_pem(sa.private_key.as_bytes()).unwrap(),
+            private_key: sa.private_key,
             token_uri: Uri::from_maybe_shared(sa.token_uri.clone()).unwrap(),
             token_uri_str: sa.token_uri,
-            scopes: sa.scopes.join(" "),
+            scopes: SCOPE_DELIMITER.join(&sa.scopes),
             client_email: sa.client_email,
+            lifetime: config.jwt_lifetime.as_secs(),
+            audiences: config.jwt_audiences.clone(),
+            extra_claims: config.extra_jwt_claims.clone(),
+            clock_offset: config.sync_clock.then(Default::default),
         }
     }
 }
@@ -71,22 +148,148 @@ impl fmt::Debug for ServiceAccount {
 }
 
 impl token::Fetcher for ServiceAccount {
-    fn fetch(&self) -> token::ResponseFuture {
-        const EXPIRE: u64 = 60 * 60;
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let extra = &self.extra_claims;
+        let offset = self.clock_offset.as_ref().map_or(0, |offset| offset.load(Ordering::Relaxed));
+        let iat = issued_at(offset);
 
-        let iat = issued_at();
+        // https://google.aip.dev/auth/4111
+        if !self.audiences.is_empty() {
+            let claims = Claims {
+                iss: &self.client_email,
+                sub: Some(&self.client_email),
+                scope: None,
+                aud: self.audiences[..].into(),
+                iat,
+                exp: iat + self.lifetime,
+                extra,
+            };
+            let resp = encode(&self.header, &claims, &self.private_key)
+                .map(|jwt| token::Response {
+                    token_type: "Bearer".to_owned(),
+                    access_token: jwt,
+                    expires_in: self.lifetime.saturating_sub(IAT_MARGIN),
+                    scope: None,
+                })
+                .map_err(auth::Error::PrivateKey);
+            return Box::pin(future::ready(resp));
+        }
+
+        let scopes = scopes.map(|scopes| SCOPE_DELIMITER.join(scopes));
+        let scope = scopes.as_deref().unwrap_or(&self.scopes);
+        trace!("fetch service account token: scope={:?}", scope);
         let claims = Claims {
             iss: &self.client_email,
-            scope: &self.scopes,
-            aud: &self.token_uri_str,
+            sub: None,
+            scope: Some(scope),
+            aud: Audience::One(&self.token_uri_str),
             iat,
-            exp: iat + EXPIRE,
+            exp: iat + self.lifetime,
+            extra,
         };
 
+        let assertion = match encode(&self.header, &claims, &self.private_key) {
+            Ok(assertion) => assertion,
+            Err(err) => return Box::pin(future::ready(Err(auth::Error::PrivateKey(err)))),
+        };
         let req = self.inner.request(&self.token_uri, &Payload {
             grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer",
-            assertion: &encode(&self.header, &claims, &self.private_key).unwrap(),
+            assertion: &assertion,
         });
-        Box::pin(self.inner.send(req))
+        match self.clock_offset {
+            Some(ref clock_offset) => {
+                let clock_offset = clock_offset.clone();
+                let fut = self.inner.send_with_date(req);
+                Box::pin(async move {
+                    match fut.await {
+                        (Err(err), Some(date)) if is_clock_skew(&err) => {
+                            let offset = offset_between(date, SystemTime::now());
+                            warn!("adjust clock offset: offset={}, err={:?}", offset, err);
+                            clock_offset.store(offset, Ordering::Relaxed);
+                            Err(auth::Error::ClockSkew(offset))
+                        }
+                        (resp, _) => resp,
+                    }
+                })
+            }
+            None => Box::pin(self.inner.send(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        let extra = Map::new();
+        let claims = Claims {
+            iss: "sa@example.com",
+            sub: Some("sa@example.com"),
+            scope: None,
+            aud: Audience::One("https://pubsub.googleapis.com/"),
+            iat: 1,
+            exp: 2,
+            extra: &extra,
+        };
+        assert_eq!(
+            serde_json::to_string(&claims).unwrap(),
+            r#"{"iss":"sa@example.com","sub":"sa@example.com","aud":"https://pubsub.googleapis.com/","iat":1,"exp":2}"#
+        );
+
+        let claims = Claims { sub: None, scope: Some("scope1 scope2"), ..claims };
+        assert_eq!(
+            serde_json::to_string(&claims).unwrap(),
+            r#"{"iss":"sa@example.com","scope":"scope1 scope2","aud":"https://pubsub.googleapis.com/","iat":1,"exp":2}"#
+        );
+
+        let mut extra = Map::new();
+        extra.insert("target_audience".into(), "https://example.com".into());
+        let claims = Claims { extra: &extra, ..claims };
+        assert_eq!(
+            serde_json::to_string(&claims).unwrap(),
+            r#"{"iss":"sa@example.com","scope":"scope1 scope2","aud":"https://pubsub.googleapis.com/","iat":1,"exp":2,"target_audience":"https://example.com"}"#
+        );
+
+        let audiences = ["https://a.example.com".to_owned(), "https://b.example.com".to_owned()];
+        let claims = Claims { aud: audiences[..].into(), extra: &Map::new(), ..claims };
+        assert_eq!(
+            serde_json::to_string(&claims).unwrap(),
+            r#"{"iss":"sa@example.com","scope":"scope1 scope2","aud":["https://a.example.com","https://b.example.com"],"iat":1,"exp":2}"#
+        );
+        let claims = Claims { aud: audiences[..1].into(), ..claims };
+        assert_eq!(
+            serde_json::to_string(&claims).unwrap(),
+            r#"{"iss":"sa@example.com","scope":"scope1 scope2","aud":"https://a.example.com","iat":1,"exp":2}"#
+        );
+    }
+
+    #[test]
+    fn test_extra_claims() {
+        let mut claims = Map::new();
+        claims.insert("custom".into(), 1.into());
+        assert!(check_extra_claims(&claims).is_ok());
+        claims.insert("aud".into(), "https://example.com".into());
+        assert!(
+            matches!(check_extra_claims(&claims), Err(auth::Error::ReservedClaim(c)) if c == "aud")
+        );
+    }
+
+    #[test]
+    fn test_offset_between() {
+        let local = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let server = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        assert_eq!(offset_between(server(1000), local), 0);
+        assert_eq!(offset_between(server(1300), local), 300);
+        assert_eq!(offset_between(server(700), local), -300);
+    }
+
+    #[test]
+    fn test_is_clock_skew() {
+        let desc = "Invalid JWT: Token must be a short-lived token (60 minutes) and in a reasonable timeframe. Check your iat and exp values in the JWT claim.";
+        assert!(is_clock_skew(&auth::Error::InvalidGrant(Some(desc.into()))));
+        assert!(!is_clock_skew(&auth::Error::InvalidGrant(Some("Bad Request".into()))));
+        assert!(!is_clock_skew(&auth::Error::InvalidGrant(None)));
     }
 }
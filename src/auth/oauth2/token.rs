@@ -4,33 +4,80 @@ use std::{
     time::{Duration, Instant},
 };
 
-use futures_util::future::BoxFuture;
+use futures_util::future::{self, BoxFuture};
 use hyper::header::HeaderValue;
+use tracing::warn;
 
-use crate::auth;
+use crate::auth::{self, RefreshThreshold};
+
+// Tokens are refreshed at least this often even if a longer lifetime is returned. This also
+// prevents the expiry from overflowing.
+const MAX_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The parts of a token, for libraries that build their own `Authorization` header or gRPC
+/// metadata. The `Authorization` header value is `"{token_type} {access_token}"`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    pub token_type: String,
+    pub access_token: String,
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken").field("token_type", &self.token_type).finish()
+    }
+}
 
 #[derive(Clone)]
 pub(crate) struct Token {
     pub value: HeaderValue,
+    pub token: AccessToken,
     pub expiry: Instant,
+    // The full lifetime of the token, used to refresh it at a fraction of the lifetime.
+    pub lifetime: Duration,
 }
 
 impl Token {
-    pub fn new(value: HeaderValue, expiry: Instant) -> Self {
-        Self { value, expiry }
+    // Returns `None` if the token cannot be a header value.
+    pub fn new(token: AccessToken, expiry: Instant, lifetime: Duration) -> Option<Self> {
+        let value = format!("{} {}", token.token_type, token.access_token);
+        let value = HeaderValue::from_str(&value).ok()?;
+        Some(Self { value, token, expiry, lifetime })
     }
 
-    pub fn expired(&self, at: Instant) -> bool {
-        const EXPIRY_DELTA: Duration = Duration::from_secs(10);
-        self.expiry.checked_duration_since(at).map(|dur| dur < EXPIRY_DELTA).unwrap_or(true)
+    /// Brings the expiry forward by `jitter`, at most half of the lifetime.
+    pub fn with_jitter(self, jitter: Duration) -> Self {
+        let jitter = jitter.min(self.lifetime / 2);
+        Self { expiry: self.expiry - jitter, lifetime: self.lifetime - jitter, ..self }
+    }
+
+    pub fn expired(&self, at: Instant, threshold: RefreshThreshold) -> bool {
+        let delta = threshold.delta(self.lifetime);
+        self.expiry.checked_duration_since(at).map(|dur| dur <= delta).unwrap_or(true)
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// Represents a token response from an OAuth 2.0 token endpoint.
+#[derive(Clone, Debug, serde::Deserialize)]
 pub struct Response {
     pub token_type: String,
     pub access_token: String,
     pub expires_in: u64,
+    /// The granted scopes delimited by spaces, if the endpoint returns them. They may be fewer
+    /// than the requested ones, e.g. if a user did not consent to all of them.
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+// Token types are case-insensitive, but some servers accept only the canonical casing, so known
+// types are normalized, e.g. `bearer` returned by emulators. Unknown types are kept as is.
+// https://datatracker.ietf.org/doc/html/rfc6749#section-5.1
+fn normalize_token_type(token_type: &str) -> String {
+    const KNOWN: [&str; 2] = ["Bearer", "DPoP"];
+    match KNOWN.iter().find(|known| known.eq_ignore_ascii_case(token_type)) {
+        Some(known) => (*known).to_owned(),
+        None => token_type.to_owned(),
+    }
 }
 
 impl TryFrom<Response> for Token {
@@ -41,18 +88,164 @@ impl TryFrom<Response> for Token {
             && !response.access_token.is_empty()
             && response.expires_in > 0
         {
-            let value = format!("{} {}", response.token_type, response.access_token);
-            if let Ok(value) = HeaderValue::from_str(&value) {
-                let expiry = Instant::now() + Duration::from_secs(response.expires_in);
-                return Ok(Token::new(value, expiry));
+            let lifetime = match Duration::from_secs(response.expires_in) {
+                lifetime if lifetime > MAX_LIFETIME => {
+                    warn!("token lifetime is clamped to {:?}: {:?}", MAX_LIFETIME, lifetime);
+                    MAX_LIFETIME
+                }
+                lifetime => lifetime,
+            };
+            let token = AccessToken {
+                token_type: normalize_token_type(&response.token_type),
+                access_token: response.access_token.clone(),
+            };
+            if let Some(token) = Token::new(token, Instant::now() + lifetime, lifetime) {
+                return Ok(token);
             }
         }
         Err(auth::Error::TokenFormat(response))
     }
 }
 
-pub(crate) type ResponseFuture = BoxFuture<'static, auth::Result<Response>>;
+/// The future returned by [`Fetcher::fetch`].
+pub type ResponseFuture = BoxFuture<'static, Result<Response, auth::Error>>;
+
+/// Fetches tokens that are cached and refreshed by [`GoogleAuthz`](crate::GoogleAuthz).
+///
+/// The trait is object safe and needs no `async_trait`: [`fetch`](Self::fetch) returns a
+/// [`ResponseFuture`], a boxed future. `fetch` is called once per attempt, including retries, so
+/// it should be cheap and free of side effects, and the actual work should be done by the
+/// returned future. Errors of the fetcher itself are reported as
+/// [`Error::Custom`](auth::Error::Custom).
+///
+/// # Example
+///
+/// A fetcher that reads tokens issued by a secret store such as Vault:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use google_authz::{AuthError, Credentials, Fetcher, ResponseFuture, TokenResponse};
+///
+/// // A client of the secret store.
+/// #[derive(Debug)]
+/// struct VaultClient;
+///
+/// impl VaultClient {
+///     async fn read(&self, path: &str) -> Result<(String, u64), std::io::Error> {
+///         Ok((format!("token-of-{}", path), 3600))
+///     }
+/// }
+///
+/// #[derive(Debug)]
+/// struct Vault {
+///     client: Arc<VaultClient>,
+///     path: String,
+/// }
+///
+/// impl Fetcher for Vault {
+///     fn fetch(&self, _: Option<&[String]>) -> ResponseFuture {
+///         // Only the handles are cloned here, the request is sent when the future is polled.
+///         let client = self.client.clone();
+///         let path = self.path.clone();
+///         Box::pin(async move {
+///             let (access_token, expires_in) =
+///                 client.read(&path).await.map_err(|err| AuthError::Custom(err.into()))?;
+///             let token_type = "Bearer".to_owned();
+///             Ok(TokenResponse { token_type, access_token, expires_in, scope: None })
+///         })
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let fetcher = Vault { client: Arc::new(VaultClient), path: "gcp/token/my-roleset".into() };
+/// let credentials = Credentials::builder().fetcher(fetcher).build().await.unwrap();
+/// # }
+/// ```
+pub trait Fetcher: fmt::Debug + Send + Sync + 'static {
+    /// Fetches a token for `scopes`, or for the scopes of the credentials if `None`.
+    fn fetch(&self, scopes: Option<&[String]>) -> ResponseFuture;
+
+    /// Fetches an ID token whose `aud` claim is `audience`. The default implementation fails
+    /// with [`Error::IdTokenUnsupported`](auth::Error::IdTokenUnsupported).
+    fn fetch_id_token(&self, audience: &str) -> ResponseFuture {
+        let _ = audience;
+        Box::pin(future::ready(Err(auth::Error::IdTokenUnsupported)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token(expiry: Instant, lifetime: Duration) -> Token {
+        let token = AccessToken { token_type: "Bearer".into(), access_token: "token".into() };
+        Token::new(token, expiry, lifetime).unwrap()
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = Instant::now();
+        let lifetime = Duration::from_secs(3600);
+        let token = token(now + lifetime, lifetime);
+
+        let delta = RefreshThreshold::Delta(Duration::from_secs(10));
+        assert!(!token.expired(now, delta));
+        assert!(!token.expired(now + Duration::from_secs(3589), delta));
+        assert!(token.expired(now + Duration::from_secs(3590), delta));
+        assert!(token.expired(now + Duration::from_secs(3601), delta));
+
+        let fraction = RefreshThreshold::Fraction(0.75);
+        assert!(!token.expired(now, fraction));
+        assert!(!token.expired(now + Duration::from_secs(2699), fraction));
+        assert!(token.expired(now + Duration::from_secs(2700), fraction));
+
+        assert!(token.expired(now + lifetime, RefreshThreshold::Fraction(1.0)));
+        assert!(token.expired(now, RefreshThreshold::Fraction(0.0)));
+    }
+
+    #[test]
+    fn test_try_from() {
+        let response = |expires_in| Response {
+            token_type: "Bearer".into(),
+            access_token: "token".into(),
+            expires_in,
+            scope: None,
+        };
+        assert_eq!(Token::try_from(response(3600)).unwrap().lifetime, Duration::from_secs(3600));
+        assert_eq!(Token::try_from(response(u64::MAX)).unwrap().lifetime, MAX_LIFETIME);
+        assert!(matches!(Token::try_from(response(0)), Err(auth::Error::TokenFormat(_))));
+
+        let token = Token::try_from(response(3600)).unwrap();
+        assert_eq!(token.value, "Bearer token");
+        assert_eq!(token.token.token_type, "Bearer");
+        assert_eq!(token.token.access_token, "token");
+        assert_eq!(format!("{:?}", token.token), r#"AccessToken { token_type: "Bearer" }"#);
+
+        let response = |token_type: &str| Response {
+            token_type: token_type.into(),
+            access_token: "token".into(),
+            expires_in: 3600,
+            scope: None,
+        };
+        assert_eq!(Token::try_from(response("bearer")).unwrap().value, "Bearer token");
+        assert_eq!(Token::try_from(response("DPOP")).unwrap().token.token_type, "DPoP");
+        assert_eq!(Token::try_from(response("mac")).unwrap().value, "mac token");
+    }
+
+    #[test]
+    fn test_with_jitter() {
+        let now = Instant::now();
+        let lifetime = Duration::from_secs(3600);
+        let token = token(now + lifetime, lifetime);
 
-pub(crate) trait Fetcher: fmt::Debug + Send + Sync + 'static {
-    fn fetch(&self) -> ResponseFuture;
+        let jittered = token.clone().with_jitter(Duration::from_secs(60));
+        assert_eq!(jittered.expiry, now + Duration::from_secs(3540));
+        assert_eq!(jittered.lifetime, Duration::from_secs(3540));
+
+        let jittered = token.with_jitter(Duration::from_secs(7200));
+        assert_eq!(jittered.expiry, now + Duration::from_secs(1800));
+        assert_eq!(jittered.lifetime, Duration::from_secs(1800));
+    }
 }
@@ -0,0 +1,87 @@
+use std::fmt;
+
+use hyper::Uri;
+use tracing::trace;
+
+use crate::{
+    auth::{
+        oauth2::{http::Client, token},
+        Config,
+    },
+    credentials,
+};
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    grant_type: &'a str,
+    subject_token: &'a str,
+    subject_token_type: &'a str,
+    requested_token_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audience: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+}
+
+// https://datatracker.ietf.org/doc/html/rfc8693
+// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+pub struct TokenExchange {
+    inner: Client,
+    token_uri: Uri,
+    credentials: credentials::TokenExchange,
+}
+
+impl TokenExchange {
+    pub(crate) fn new(te: credentials::TokenExchange, config: &Config) -> Self {
+        Self {
+            inner: Client::new(config),
+            // Already checked when the credentials were built.
+            token_uri: Uri::from_maybe_shared(te.token_uri.clone()).unwrap(),
+            credentials: te,
+        }
+    }
+}
+
+impl fmt::Debug for TokenExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenExchange").field("token_uri", &self.token_uri).finish()
+    }
+}
+
+impl token::Fetcher for TokenExchange {
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let scopes = scopes.unwrap_or(&self.credentials.scopes);
+        let scope = (!scopes.is_empty()).then(|| self.credentials.scope_delimiter.join(scopes));
+        trace!("exchange token: scope={:?}", scope);
+        let req = self.inner.request(&self.token_uri, &Payload {
+            grant_type: &self.credentials.grant_type,
+            subject_token: &self.credentials.subject_token,
+            subject_token_type: &self.credentials.subject_token_type,
+            requested_token_type: &self.credentials.requested_token_type,
+            audience: self.credentials.audience.as_deref(),
+            scope: scope.as_deref(),
+        });
+        Box::pin(self.inner.send(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let payload = Payload {
+            grant_type: "urn:ietf:params:oauth:grant-type:token-exchange",
+            subject_token: "subject",
+            subject_token_type: "urn:ietf:params:oauth:token-type:jwt",
+            requested_token_type: "urn:ietf:params:oauth:token-type:access_token",
+            audience: None,
+            scope: Some("scope1 scope2"),
+        };
+        assert_eq!(
+            serde_urlencoded::to_string(&payload).unwrap(),
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange&subject_token=subject&subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt&requested_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Aaccess_token&scope=scope1+scope2"
+        );
+    }
+}
@@ -1,18 +1,38 @@
 use std::fmt;
 
-use hyper::Uri;
+use bytes::Bytes;
+use hyper::{header::HeaderValue, Request, Uri};
+use tracing::trace;
 
 use crate::{
-    auth::oauth2::{http::Client, token},
+    auth::{
+        self,
+        oauth2::{http::Client, token, with_timeout},
+        Config, ScopeDelimiter,
+    },
     credentials,
 };
 
+// https://datatracker.ietf.org/doc/html/rfc6749#section-3.3
+const SCOPE_DELIMITER: ScopeDelimiter = ScopeDelimiter::Space;
+
+// https://developers.google.com/identity/openid-connect/openid-connect#obtaininguserprofileinformation
+pub(crate) const USERINFO_EMAIL_SCOPE: &str = "https://www.googleapis.com/auth/userinfo.email";
+const USERINFO_URI: &str = "https://openidconnect.googleapis.com/v1/userinfo";
+
+#[derive(serde::Deserialize)]
+struct UserInfo {
+    email: Option<String>,
+}
+
 #[derive(serde::Serialize)]
 struct Payload<'a> {
     client_id: &'a str,
     client_secret: &'a str,
     grant_type: &'a str,
     refresh_token: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
 }
 
 // https://cloud.google.com/docs/authentication/end-user
@@ -23,16 +43,36 @@ pub struct User {
 }
 
 impl User {
-    pub(crate) fn new(user: credentials::User) -> Self {
+    pub(crate) fn new(user: credentials::User, config: &Config) -> Self {
         Self {
-            inner: Client::new(),
-            // https://github.com/golang/oauth2/blob/0f29369cfe4552d0e4bcddc57cc75f4d7e672a33/google/google.go#L24
-            token_uri: Uri::from_static("https://oauth2.googleapis.com/token"),
+            inner: Client::new(config),
+            token_uri: Uri::from_maybe_shared(user.token_uri.clone()).unwrap(),
             credentials: user,
         }
     }
 }
 
+/// Fetches the email of the account that authorized `user`, which needs the `userinfo.email` scope.
+pub(crate) async fn fetch_email(
+    user: &credentials::User,
+    config: &Config,
+) -> auth::Result<Option<String>> {
+    let client = Client::new(config);
+    let token_uri = Uri::from_maybe_shared(user.token_uri.clone()).unwrap();
+    let scopes = SCOPE_DELIMITER.join(&user.scopes);
+    let req = request(&client, &token_uri, user, Some(&scopes));
+    with_timeout(config.fetch_timeout, async move {
+        let resp: token::Response = client.send(req).await?;
+        let value = format!("{} {}", resp.token_type, resp.access_token);
+        let authorization =
+            HeaderValue::from_str(&value).map_err(|_| auth::Error::TokenFormat(resp))?;
+        let req = client.get(&Uri::from_static(USERINFO_URI), authorization);
+        let info: UserInfo = client.send(req).await?;
+        Ok(info.email)
+    })
+    .await
+}
+
 impl fmt::Debug for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("User").finish()
@@ -40,15 +80,29 @@ impl fmt::Debug for User {
 }
 
 impl token::Fetcher for User {
-    fn fetch(&self) -> token::ResponseFuture {
-        let req = self.inner.request(&self.token_uri, &Payload {
-            client_id: &self.credentials.client_id,
-            client_secret: &self.credentials.client_secret,
-            grant_type: "refresh_token",
-            // The reflesh token is not included in the response from google's server,
-            // so it always uses the specified refresh token from the file.
-            refresh_token: &self.credentials.refresh_token,
-        });
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let scopes = scopes.map(|scopes| SCOPE_DELIMITER.join(scopes));
+        // The scopes of the refresh token are used if none are requested.
+        trace!("fetch user token: scope={:?}", scopes);
+        // Narrows the scopes of the token only when they are explicitly overridden.
+        let req = request(&self.inner, &self.token_uri, &self.credentials, scopes.as_deref());
         Box::pin(self.inner.send(req))
     }
 }
+
+fn request(
+    client: &Client,
+    token_uri: &Uri,
+    user: &credentials::User,
+    scope: Option<&str>,
+) -> Request<Bytes> {
+    client.request(token_uri, &Payload {
+        client_id: &user.client_id,
+        client_secret: &user.client_secret,
+        grant_type: "refresh_token",
+        // The reflesh token is not included in the response from google's server,
+        // so it always uses the specified refresh token from the file.
+        refresh_token: &user.refresh_token,
+        scope,
+    })
+}
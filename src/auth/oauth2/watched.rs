@@ -0,0 +1,152 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    auth::{self, oauth2::token, Config},
+    credentials::{self, Credentials, SourceKind},
+};
+
+// Reloads credentials from their file when it is modified, e.g. when a mounted secret is rotated
+// in place. The file is checked each time a token is fetched, so no background task is needed.
+pub struct Watched {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    scopes: Vec<String>,
+    source: SourceKind,
+    token_uri: Option<String>,
+    config: Config,
+    state: Mutex<State>,
+}
+
+struct State {
+    modified: Option<SystemTime>,
+    fetcher: Arc<dyn token::Fetcher>,
+}
+
+impl Watched {
+    pub(crate) fn new(credentials: Credentials, path: PathBuf, config: &Config) -> Self {
+        let scopes = credentials.describe().scopes;
+        let source = credentials.source_kind();
+        let token_uri = credentials.token_uri_override().map(ToOwned::to_owned);
+        let state = State {
+            modified: modified(&path),
+            fetcher: auth::plain_fetcher(credentials, config).into(),
+        };
+        let inner = Inner {
+            path,
+            scopes,
+            source,
+            token_uri,
+            config: config.clone(),
+            state: Mutex::new(state),
+        };
+        Self { inner: Arc::new(inner) }
+    }
+}
+
+impl Inner {
+    // Returns the fetcher of the current credentials. The old credentials are kept if the
+    // modified file cannot be read, e.g. while it is being written.
+    fn fetcher(&self) -> Arc<dyn token::Fetcher> {
+        let mut state = self.state.lock();
+        let modified = modified(&self.path);
+        if modified.is_some() && modified != state.modified {
+            match credentials::reload(
+                &self.path,
+                &self.scopes,
+                self.source,
+                self.token_uri.as_deref(),
+            ) {
+                Ok(credentials) => {
+                    info!("reloaded credentials from {:?}", self.path);
+                    state.fetcher = auth::plain_fetcher(credentials, &self.config).into();
+                    state.modified = modified;
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to reload credentials from {:?}, keep using the old ones: {}",
+                        self.path, err
+                    );
+                }
+            }
+        }
+        state.fetcher.clone()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl fmt::Debug for Watched {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watched").field("path", &self.inner.path).finish()
+    }
+}
+
+impl token::Fetcher for Watched {
+    fn fetch(&self, scopes: Option<&[String]>) -> token::ResponseFuture {
+        let inner = self.inner.clone();
+        let scopes = scopes.map(<[String]>::to_vec);
+        Box::pin(async move { inner.fetcher().fetch(scopes.as_deref()).await })
+    }
+
+    fn fetch_id_token(&self, audience: &str) -> token::ResponseFuture {
+        let inner = self.inner.clone();
+        let audience = audience.to_owned();
+        Box::pin(async move { inner.fetcher().fetch_id_token(&audience).await })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn user(client_id: &str) -> String {
+        format!(
+            r#"{{"client_id":"{}","client_secret":"secret","refresh_token":"refresh","type":"authorized_user"}}"#,
+            client_id
+        )
+    }
+
+    #[test]
+    fn test_reload() {
+        let path =
+            std::env::temp_dir().join(format!("google-authz-watched-{}.json", std::process::id()));
+        let write = |json: &str, secs| {
+            fs::write(&path, json).unwrap();
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+
+        write(&user("first"), 1);
+        let credentials =
+            credentials::reload(&path, &["scope".into()], SourceKind::JsonFile, None).unwrap();
+        let watched = Watched::new(credentials, path.clone(), &Config::default());
+        let first = watched.inner.fetcher();
+        assert!(Arc::ptr_eq(&first, &watched.inner.fetcher()));
+
+        // An invalid file is ignored, and read again when it is modified.
+        write("{", 2);
+        assert!(Arc::ptr_eq(&first, &watched.inner.fetcher()));
+        write(&user("second"), 3);
+        let second = watched.inner.fetcher();
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        // The old credentials are kept while the file is missing.
+        fs::remove_file(&path).unwrap();
+        assert!(Arc::ptr_eq(&second, &watched.inner.fetcher()));
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use futures_util::future;
+use hyper::{header::HeaderValue, Request};
+
+use crate::{auth, service::Builder, Credentials, GoogleAuthz};
+
+/// Authorizes `http::Request`s without `tower_service`, e.g. in plain `http` pipelines.
+///
+/// The token is fetched or refreshed when a request is authorized, and it is shared with the
+/// clones of this value and the [`GoogleAuthz`] it is derived from.
+///
+/// It is also a handle of resolved credentials and their token cache, so that many services,
+/// e.g. tonic channels, can be built with [`GoogleAuthz::from_auth`] without resolving the
+/// credentials again.
+#[derive(Clone, Debug)]
+pub struct Authorizer {
+    pub(crate) auth: auth::Auth,
+}
+
+impl Authorizer {
+    pub(crate) fn from_auth(auth: auth::Auth) -> Self {
+        Self { auth }
+    }
+
+    /// Returns an `Authorizer` that uses the default credentials.
+    pub async fn new() -> Self {
+        Self::builder().build_authorizer().await
+    }
+
+    /// Returns a builder to configure the `Authorizer`. The service of the builder is not used.
+    pub fn builder() -> Builder<()> {
+        GoogleAuthz::builder(())
+    }
+
+    /// Waits for a valid token and adds it to `req`, or the API key if the credentials use one.
+    pub async fn authorize<B>(&mut self, req: Request<B>) -> Result<Request<B>, auth::Error> {
+        future::poll_fn(|cx| self.auth.poll_ready(cx)).await?;
+        self.auth.call(req)
+    }
+
+    /// See [`GoogleAuthz::authorization_header`].
+    pub async fn authorization_header(&mut self) -> Result<HeaderValue, auth::Error> {
+        self.auth.token().await?.ok_or(auth::Error::TokenUnsupported)
+    }
+
+    /// See [`GoogleAuthz::access_token`].
+    pub async fn access_token(&mut self) -> Result<Option<auth::AccessToken>, auth::Error> {
+        self.auth.access_token().await
+    }
+
+    /// See [`GoogleAuthz::invalidate_token`].
+    pub fn invalidate_token(&self) {
+        self.auth.invalidate_token()
+    }
+
+    /// See [`GoogleAuthz::set_credentials`].
+    pub fn set_credentials(&self, credentials: Credentials) {
+        self.auth.set_credentials(credentials)
+    }
+
+    /// See [`GoogleAuthz::token_ttl`].
+    pub fn token_ttl(&self) -> Option<Duration> {
+        self.auth.token_ttl()
+    }
+
+    /// See [`GoogleAuthz::is_token_valid`].
+    pub fn is_token_valid(&self) -> bool {
+        self.auth.is_token_valid()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::header::AUTHORIZATION;
+
+    use super::*;
+    use crate::MockFetcher;
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let credentials = Credentials::builder()
+            .fetcher(MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut authorizer =
+            Authorizer::builder().credentials(credentials).build_authorizer().await;
+        assert!(!authorizer.is_token_valid());
+
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        let req = authorizer.authorize(req).await.unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+        assert!(authorizer.token_ttl().is_some());
+        assert!(authorizer.is_token_valid());
+        authorizer.invalidate_token();
+        assert!(!authorizer.is_token_valid());
+
+        let token = authorizer.access_token().await.unwrap().unwrap();
+        assert_eq!((token.token_type.as_str(), token.access_token.as_str()), ("Bearer", "token"));
+    }
+}
@@ -4,17 +4,49 @@ pub enum Error {
     #[error("gcemeta client error: {0}")]
     Gcemeta(#[from] gcemeta::Error),
     #[error("api key format error: {0}")]
-    ApiKeyFormat(hyper::http::uri::InvalidUri),
+    ApiKeyFormat(String),
+    #[error("api key does not look like a google api key")]
+    UnrecognizedApiKey,
     #[error(
         "not found credentials source, please set the environment variable `RUST_LOG` to `google_authz=trace` for more details"
     )]
     CredentialsSource,
-    #[error("read credentials file error: {0}")]
-    CredentialsFile(std::io::Error),
+    #[error("read credentials file error: {}: {source}", .path.display())]
+    CredentialsFile { path: std::path::PathBuf, source: std::io::Error },
     #[error(
         "user or service account credentials format error: user={user}, service_account={service_account})"
     )]
     CredentialsFormat { user: serde_json::Error, service_account: serde_json::Error },
+    #[error("metadata account must be `default`, an email or a numeric id: {0:?}")]
+    MetadataAccount(String),
+    #[error("service account credentials need either `private_key` or `private_key_path`")]
+    PrivateKeySource,
+    #[error("private key must be an rsa key in pkcs#1 or pkcs#8 pem: {0}")]
+    PrivateKey(#[source] jsonwebtoken::errors::Error),
+    #[error("scopes must not be empty for user or service account credentials")]
+    EmptyScopes,
+    #[error(
+        "scope must be an https url, e.g. `https://www.googleapis.com/auth/cloud-platform`: {0:?}"
+    )]
+    InvalidScope(String),
+    #[error("token uri format error: {0}")]
+    TokenUriFormat(hyper::http::uri::InvalidUri),
+    #[error("secret version name must be `projects/*/secrets/*/versions/*`: {0:?}")]
+    SecretName(String),
+    #[error("secret manager error: {name}: {source}")]
+    SecretManager { name: String, source: crate::auth::Error },
+    #[error("secret payload is not valid base64: {0}")]
+    SecretPayload(String),
+    #[error("impersonation error: {0}")]
+    Impersonation(String),
+    #[error("all credentials sources failed: {}", display_sources(.0))]
+    Sources(Vec<(String, Error)>),
+}
+
+fn display_sources(errors: &[(String, Error)]) -> String {
+    let errors: Vec<_> =
+        errors.iter().map(|(source, err)| format!("{}: {}", source, err)).collect();
+    errors.join(", ")
 }
 
 /// Wrapper for the `Result` type with an [`Error`](Error).
@@ -1,6 +1,14 @@
-use std::path::Path;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use hyper::client::HttpConnector;
+use hyper::{client::HttpConnector, Uri};
+use jsonwebtoken::EncodingKey;
+use tracing::trace;
+
+use crate::auth::{self, Fetcher, ScopeDelimiter};
 
 mod error;
 mod impls;
@@ -8,13 +16,17 @@ mod impls;
 pub use error::*;
 
 #[cfg_attr(test, derive(PartialEq))]
-#[derive(Debug)]
 pub enum Credentials {
     None,
     ApiKey(String),
     User(User),
     ServiceAccount(ServiceAccount),
     Metadata(Box<Metadata>),
+    GcloudCli,
+    Fetcher(Box<dyn Fetcher>),
+    Impersonated(Box<Impersonated>),
+    TokenExchange(Box<TokenExchange>),
+    ClientCredentials(Box<ClientCredentials>),
 }
 
 impl Credentials {
@@ -25,70 +37,617 @@ impl Credentials {
     pub fn builder<'a>() -> Builder<'a> {
         Builder::default()
     }
+
+    /// Returns information about these credentials that is safe to log.
+    /// Secrets such as private keys, refresh tokens and API keys are never included.
+    pub fn describe(&self) -> CredentialsInfo {
+        let (kind, account, key_id) = match self {
+            Self::None => (CredentialsKind::None, None, None),
+            Self::ApiKey(_) => (CredentialsKind::ApiKey, None, None),
+            Self::User(user) => {
+                (CredentialsKind::User, user.email.clone(), Some(user.client_id.clone()))
+            }
+            Self::ServiceAccount(sa) => (
+                CredentialsKind::ServiceAccount,
+                Some(sa.client_email.clone()),
+                Some(sa.private_key_id.clone()),
+            ),
+            Self::Metadata(meta) => (CredentialsKind::Metadata, meta.account.clone(), None),
+            Self::GcloudCli => (CredentialsKind::GcloudCli, None, None),
+            Self::Fetcher(_) => (CredentialsKind::Fetcher, None, None),
+            Self::Impersonated(imp) => {
+                (CredentialsKind::Impersonated, Some(imp.target_principal.clone()), None)
+            }
+            Self::TokenExchange(_) => (CredentialsKind::TokenExchange, None, None),
+            Self::ClientCredentials(cc) => {
+                (CredentialsKind::ClientCredentials, None, Some(cc.client_id.clone()))
+            }
+        };
+        let scopes = self.scopes().to_vec();
+        CredentialsInfo { kind, account, key_id, scopes }
+    }
+
+    /// Returns the scopes these credentials were built with, e.g. to derive a service with
+    /// narrower scopes. Impersonated credentials return the scopes of the impersonated service
+    /// account. Credentials without scopes, e.g. API keys and custom fetchers, return none.
+    pub fn scopes(&self) -> &[String] {
+        match self {
+            Self::User(user) => &user.scopes,
+            Self::ServiceAccount(sa) => &sa.scopes,
+            Self::Metadata(meta) => &meta.scopes,
+            Self::Impersonated(imp) => &imp.scopes,
+            Self::TokenExchange(te) => &te.scopes,
+            Self::ClientCredentials(cc) => &cc.scopes,
+            Self::None | Self::ApiKey(_) | Self::GcloudCli | Self::Fetcher(_) => &[],
+        }
+    }
+
+    /// Returns where these credentials were found, e.g. to log whether the default resolution
+    /// picked a key file or the metadata server.
+    pub fn source_kind(&self) -> SourceKind {
+        match self {
+            Self::None => SourceKind::None,
+            Self::ApiKey(_) => SourceKind::ApiKey,
+            Self::User(user) => user.source,
+            Self::ServiceAccount(sa) => sa.source,
+            Self::Metadata(_) => SourceKind::Metadata,
+            Self::GcloudCli => SourceKind::GcloudCli,
+            Self::Fetcher(_) => SourceKind::Fetcher,
+            Self::Impersonated(imp) => imp.source.source_kind(),
+            Self::TokenExchange(_) => SourceKind::TokenExchange,
+            Self::ClientCredentials(_) => SourceKind::ClientCredentials,
+        }
+    }
+
+    // Records the source of credentials that are loaded from JSON.
+    fn with_source(mut self, source: SourceKind) -> Self {
+        match self {
+            Self::User(ref mut user) => user.source = source,
+            Self::ServiceAccount(ref mut sa) => sa.source = source,
+            _ => {}
+        }
+        self
+    }
+
+    // Records the file of credentials that are loaded from a JSON file.
+    fn with_path(mut self, path: &Path) -> Self {
+        match self {
+            Self::User(ref mut user) => user.path = Some(path.to_owned()),
+            Self::ServiceAccount(ref mut sa) => sa.path = Some(path.to_owned()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Returns the file these credentials were read from, if any.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            Self::User(user) => user.path.as_deref(),
+            Self::ServiceAccount(sa) => sa.path.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the token endpoint set with [`Builder::token_uri`], if any.
+    pub(crate) fn token_uri_override(&self) -> Option<&str> {
+        match self {
+            Self::ServiceAccount(sa) => sa.token_uri_override.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the project id of these credentials, which is looked for in the following places
+    /// when they are built:
+    /// - The `project_id` field of a service account key.
+    /// - The `GOOGLE_CLOUD_PROJECT` or `GCLOUD_PROJECT` environment variable.
+    /// - On Google Compute Engine, the project of the metadata server.
+    ///
+    /// Impersonated credentials return the project id of the source credentials.
+    pub fn project_id(&self) -> Option<&str> {
+        match self {
+            Self::User(user) => user.project_id.as_deref(),
+            Self::ServiceAccount(sa) => sa.project_id.as_deref(),
+            Self::Metadata(meta) => meta.project_id.as_deref(),
+            Self::Impersonated(imp) => imp.source.project_id(),
+            _ => None,
+        }
+    }
+
+    /// Reports whether both credentials have the same identity, i.e. the same kind,
+    /// account, key id and scopes. Secrets are not compared.
+    pub fn same_identity(&self, other: &Self) -> bool {
+        self.describe() == other.describe()
+    }
+}
+
+/// Reads credentials again from `path`, e.g. after the key is rotated, keeping the scopes, the
+/// source and the overridden token endpoint of the credentials that were read first.
+pub(crate) fn reload(
+    path: &Path,
+    scopes: &[String],
+    source: SourceKind,
+    token_uri: Option<&str>,
+) -> Result<Credentials> {
+    let credentials = impls::from_json_file(path, scopes).map(|c| c.with_source(source))?;
+    match token_uri {
+        Some(token_uri) => impls::override_token_uri(credentials, token_uri.to_owned()),
+        None => Ok(credentials),
+    }
+}
+
+/// Serializes [`Credentials::describe`], so secrets are never serialized.
+impl serde::Serialize for Credentials {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.describe().serialize(serializer)
+    }
+}
+
+/// Represents the kind of [`Credentials`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+pub enum CredentialsKind {
+    None,
+    ApiKey,
+    User,
+    ServiceAccount,
+    Metadata,
+    GcloudCli,
+    Fetcher,
+    Impersonated,
+    TokenExchange,
+    ClientCredentials,
+}
+
+/// Non-secret information about [`Credentials`], returned by [`Credentials::describe`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct CredentialsInfo {
+    pub kind: CredentialsKind,
+    /// The service account email, the metadata server account if specified, the impersonated
+    /// service account, or the user email found with [`Builder::user_email`].
+    pub account: Option<String>,
+    /// The service account private key id, or the OAuth 2.0 client id of user or client
+    /// credentials.
+    pub key_id: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+/// Represents where [`Credentials`] were found, returned by [`Credentials::source_kind`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize)]
+pub enum SourceKind {
+    None,
+    ApiKey,
+    /// JSON data passed to [`Builder::json`].
+    #[default]
+    Json,
+    /// A JSON file passed to [`Builder::json_file`].
+    JsonFile,
+    /// The `GOOGLE_APPLICATION_CREDENTIALS_JSON` environment variable.
+    EnvJson,
+    /// The JSON file specified by the `GOOGLE_APPLICATION_CREDENTIALS` environment variable.
+    EnvFile,
+    /// The JSON file in a location known to the gcloud command-line tool.
+    WellKnownFile,
+    Metadata,
+    GcloudCli,
+    Fetcher,
+    /// A [`TokenExchange`] passed to [`Builder::token_exchange`].
+    TokenExchange,
+    /// [`ClientCredentials`] passed to [`Builder::client_credentials`].
+    ClientCredentials,
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
-#[derive(Debug, serde::Deserialize)]
+#[derive(serde::Deserialize)]
 pub struct User {
     #[serde(skip)]
-    pub(crate) scopes: &'static [&'static str],
+    pub(crate) scopes: Vec<String>,
+    #[serde(skip)]
+    pub(crate) source: SourceKind,
+    // The file the credentials were read from, if any.
+    #[serde(skip)]
+    pub(crate) path: Option<PathBuf>,
+    // Read from the environment, user credentials do not belong to a project.
+    #[serde(skip)]
+    pub(crate) project_id: Option<String>,
+    // Found with `Builder::user_email`.
+    #[serde(skip)]
+    pub(crate) email: Option<String>,
     // json fields
     pub(crate) client_id: String,
     pub(crate) client_secret: String,
     pub(crate) refresh_token: String,
+    // Derived from `universe_domain` if it is not present.
+    #[serde(default)]
+    pub(crate) token_uri: String,
+    #[serde(default = "default_universe_domain")]
+    pub(crate) universe_domain: String,
 }
 
-#[cfg_attr(test, derive(PartialEq, Eq))]
-#[derive(Debug, serde::Deserialize)]
+// Built from a key file by `impls::from_json`, which parses the private key.
 pub struct ServiceAccount {
-    #[serde(skip)]
-    pub(crate) scopes: &'static [&'static str],
-    // json fields
+    pub(crate) scopes: Vec<String>,
+    pub(crate) source: SourceKind,
+    // The file the credentials were read from, if any.
+    pub(crate) path: Option<PathBuf>,
+    // Set with `Builder::token_uri`, it wins over `token_uri` when the file is reloaded.
+    pub(crate) token_uri_override: Option<String>,
+    pub(crate) project_id: Option<String>,
     pub(crate) client_email: String,
     pub(crate) private_key_id: String,
-    pub(crate) private_key: String,
+    pub(crate) private_key: EncodingKey,
+    pub(crate) private_key_path: Option<PathBuf>,
     pub(crate) token_uri: String,
+    pub(crate) universe_domain: String,
+}
+
+// `EncodingKey` cannot be compared, so the private key is not.
+#[cfg(test)]
+impl PartialEq for ServiceAccount {
+    fn eq(&self, other: &Self) -> bool {
+        self.scopes == other.scopes
+            && self.source == other.source
+            && self.path == other.path
+            && self.token_uri_override == other.token_uri_override
+            && self.project_id == other.project_id
+            && self.client_email == other.client_email
+            && self.private_key_id == other.private_key_id
+            && self.private_key_path == other.private_key_path
+            && self.token_uri == other.token_uri
+            && self.universe_domain == other.universe_domain
+    }
+}
+
+// Shown in place of secrets by the `Debug` implementations.
+const REDACTED: &str = "***";
+
+// The API key is a secret.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::ApiKey(_) => f.debug_tuple("ApiKey").field(&REDACTED).finish(),
+            Self::User(user) => f.debug_tuple("User").field(user).finish(),
+            Self::ServiceAccount(sa) => f.debug_tuple("ServiceAccount").field(sa).finish(),
+            Self::Metadata(meta) => f.debug_tuple("Metadata").field(meta).finish(),
+            Self::GcloudCli => f.write_str("GcloudCli"),
+            Self::Fetcher(fetcher) => f.debug_tuple("Fetcher").field(fetcher).finish(),
+            Self::Impersonated(imp) => f.debug_tuple("Impersonated").field(imp).finish(),
+            Self::TokenExchange(te) => f.debug_tuple("TokenExchange").field(te).finish(),
+            Self::ClientCredentials(cc) => f.debug_tuple("ClientCredentials").field(cc).finish(),
+        }
+    }
+}
+
+// The client secret and the refresh token are secrets.
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("scopes", &self.scopes)
+            .field("source", &self.source)
+            .field("path", &self.path)
+            .field("project_id", &self.project_id)
+            .field("email", &self.email)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .field("token_uri", &self.token_uri)
+            .field("universe_domain", &self.universe_domain)
+            .finish()
+    }
+}
+
+// The private key is a secret.
+impl fmt::Debug for ServiceAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServiceAccount")
+            .field("scopes", &self.scopes)
+            .field("source", &self.source)
+            .field("path", &self.path)
+            .field("project_id", &self.project_id)
+            .field("client_email", &self.client_email)
+            .field("private_key_id", &self.private_key_id)
+            .field("private_key", &REDACTED)
+            .field("private_key_path", &self.private_key_path)
+            .field("token_uri", &self.token_uri)
+            .field("token_uri_override", &self.token_uri_override)
+            .field("universe_domain", &self.universe_domain)
+            .finish()
+    }
+}
+
+// https://google.aip.dev/auth/4116
+pub(crate) const DEFAULT_UNIVERSE_DOMAIN: &str = "googleapis.com";
+
+fn default_universe_domain() -> String {
+    DEFAULT_UNIVERSE_DOMAIN.to_owned()
+}
+
+// Returns the OAuth 2.0 token endpoint of `universe_domain`,
+// e.g. `https://oauth2.googleapis.com/token`.
+// https://github.com/golang/oauth2/blob/0f29369cfe4552d0e4bcddc57cc75f4d7e672a33/google/google.go#L24
+fn token_uri(universe_domain: &str) -> String {
+    format!("https://oauth2.{}/token", universe_domain)
 }
 
 #[derive(Debug)]
 pub struct Metadata {
     pub(crate) client: gcemeta::Client<HttpConnector>,
-    pub(crate) scopes: &'static [&'static str],
+    pub(crate) scopes: Vec<String>,
     pub(crate) account: Option<String>,
+    pub(crate) timeout: Option<Duration>,
+    // Overrides the host of the metadata server, e.g. a local metadata proxy.
+    pub(crate) host: Option<Uri>,
+    pub(crate) project_id: Option<String>,
+}
+
+/// Credentials that impersonate a service account with the IAM Credentials API, using the
+/// source credentials to authorize the calls.
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug)]
+pub struct Impersonated {
+    pub(crate) source: Credentials,
+    pub(crate) target_principal: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) id_token: Option<IdToken>,
+}
+
+// https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateIdToken
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Debug)]
+pub struct IdToken {
+    pub(crate) audience: String,
+    pub(crate) include_email: bool,
+}
+
+// https://datatracker.ietf.org/doc/html/rfc8693#section-2.1
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// Credentials that exchange a subject token for an access token at a security token service,
+/// e.g. `https://sts.googleapis.com/v1/token`, with the OAuth 2.0 token exchange (RFC 8693).
+///
+/// This is the primitive underneath workload identity federation, where the subject token is
+/// issued by an external identity provider.
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Clone)]
+pub struct TokenExchange {
+    pub(crate) token_uri: String,
+    pub(crate) grant_type: String,
+    pub(crate) subject_token: String,
+    pub(crate) subject_token_type: String,
+    pub(crate) audience: Option<String>,
+    pub(crate) requested_token_type: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) scope_delimiter: ScopeDelimiter,
+}
+
+impl TokenExchange {
+    /// Returns credentials that post `subject_token` of `subject_token_type`, e.g.
+    /// `urn:ietf:params:oauth:token-type:jwt`, to `token_uri`. The scopes are the ones set with
+    /// [`Builder::scopes`].
+    pub fn new(
+        token_uri: impl Into<String>,
+        subject_token: impl Into<String>,
+        subject_token_type: impl Into<String>,
+    ) -> Self {
+        Self {
+            token_uri: token_uri.into(),
+            grant_type: TOKEN_EXCHANGE_GRANT_TYPE.to_owned(),
+            subject_token: subject_token.into(),
+            subject_token_type: subject_token_type.into(),
+            audience: None,
+            requested_token_type: ACCESS_TOKEN_TYPE.to_owned(),
+            scopes: Vec::new(),
+            scope_delimiter: ScopeDelimiter::default(),
+        }
+    }
+
+    /// Overrides the grant type, which defaults to
+    /// `urn:ietf:params:oauth:grant-type:token-exchange`.
+    #[must_use]
+    pub fn grant_type(mut self, grant_type: impl Into<String>) -> Self {
+        self.grant_type = grant_type.into();
+        self
+    }
+
+    /// Sets the audience, e.g. the workload identity pool provider
+    /// `//iam.googleapis.com/projects/.../providers/...`.
+    #[must_use]
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Overrides the requested token type, which defaults to
+    /// `urn:ietf:params:oauth:token-type:access_token`.
+    #[must_use]
+    pub fn requested_token_type(mut self, requested_token_type: impl Into<String>) -> Self {
+        self.requested_token_type = requested_token_type.into();
+        self
+    }
+
+    /// Overrides how the scopes are joined in the `scope` parameter, which defaults to
+    /// [`ScopeDelimiter::Space`] as in OAuth 2.0.
+    #[must_use]
+    pub fn scope_delimiter(mut self, scope_delimiter: ScopeDelimiter) -> Self {
+        self.scope_delimiter = scope_delimiter;
+        self
+    }
+}
+
+// The subject token is a secret.
+impl fmt::Debug for TokenExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenExchange")
+            .field("token_uri", &self.token_uri)
+            .field("grant_type", &self.grant_type)
+            .field("subject_token_type", &self.subject_token_type)
+            .field("audience", &self.audience)
+            .field("requested_token_type", &self.requested_token_type)
+            .field("scopes", &self.scopes)
+            .field("scope_delimiter", &self.scope_delimiter)
+            .finish()
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc6749#section-4.4.2
+pub(crate) const CLIENT_CREDENTIALS_GRANT_TYPE: &str = "client_credentials";
+
+/// Credentials that fetch access tokens with the OAuth 2.0 client credentials grant (RFC 6749),
+/// e.g. from an OAuth 2.0 server that is not Google's. The client id and secret are sent in the
+/// request body.
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Clone)]
+pub struct ClientCredentials {
+    pub(crate) token_uri: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) scope_delimiter: ScopeDelimiter,
+}
+
+impl ClientCredentials {
+    /// Returns credentials that post `client_id` and `client_secret` to `token_uri`. The scopes
+    /// are the ones set with [`Builder::scopes`].
+    pub fn new(
+        token_uri: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        Self {
+            token_uri: token_uri.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: Vec::new(),
+            scope_delimiter: ScopeDelimiter::default(),
+        }
+    }
+
+    /// Overrides how the scopes are joined in the `scope` parameter, which defaults to
+    /// [`ScopeDelimiter::Space`] as in OAuth 2.0.
+    #[must_use]
+    pub fn scope_delimiter(mut self, scope_delimiter: ScopeDelimiter) -> Self {
+        self.scope_delimiter = scope_delimiter;
+        self
+    }
+}
+
+// The client secret is a secret.
+impl fmt::Debug for ClientCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCredentials")
+            .field("token_uri", &self.token_uri)
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .field("scope_delimiter", &self.scope_delimiter)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for dyn Fetcher {
+    fn eq(&self, other: &Self) -> bool {
+        format!("{:?}", self) == format!("{:?}", other)
+    }
 }
 
 #[cfg(test)]
 impl PartialEq for Metadata {
     fn eq(&self, other: &Self) -> bool {
-        self.scopes == other.scopes && self.account == other.account
+        self.scopes == other.scopes
+            && self.account == other.account
+            && self.timeout == other.timeout
+            && self.host == other.host
+            && self.project_id == other.project_id
     }
 }
 
-enum Source<'a> {
+/// Represents a place to look for credentials.
+pub enum Source<'a> {
     None,
     Default,
     ApiKey { key: String },
     Json { data: &'a [u8] },
     JsonFile { path: &'a Path },
     Metadata { account: Option<String> },
+    GcloudCli,
+    Fetcher(Box<dyn Fetcher>),
+    TokenExchange(TokenExchange),
+    ClientCredentials(ClientCredentials),
+    SecretManager { name: String },
+}
+
+impl<'a> fmt::Debug for Source<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::Default => write!(f, "Default"),
+            Self::ApiKey { .. } => write!(f, "ApiKey"),
+            Self::Json { .. } => write!(f, "Json"),
+            Self::JsonFile { path } => f.debug_struct("JsonFile").field("path", path).finish(),
+            Self::Metadata { account } => {
+                f.debug_struct("Metadata").field("account", account).finish()
+            }
+            Self::GcloudCli => write!(f, "GcloudCli"),
+            Self::Fetcher(fetcher) => f.debug_tuple("Fetcher").field(fetcher).finish(),
+            Self::TokenExchange(te) => f.debug_tuple("TokenExchange").field(te).finish(),
+            Self::ClientCredentials(cc) => f.debug_tuple("ClientCredentials").field(cc).finish(),
+            Self::SecretManager { name } => {
+                f.debug_struct("SecretManager").field("name", name).finish()
+            }
+        }
+    }
 }
 
+#[allow(clippy::derivable_impls)]
 impl<'a> Default for Source<'a> {
     fn default() -> Self {
         Self::Default
     }
 }
 
+const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+
 pub struct Builder<'a> {
-    scopes: &'static [&'static str],
-    source: Source<'a>,
+    scopes: Vec<String>,
+    sources: Vec<Source<'a>>,
+    metadata_timeout: Option<Duration>,
+    metadata_host: Option<String>,
+    metadata_default_scopes: bool,
+    disable_metadata: bool,
+    strict_api_key: bool,
+    validate_scopes: bool,
+    metadata_client: Option<gcemeta::Client<HttpConnector>>,
+    token_uri: Option<String>,
+    user_email: bool,
+    target_principal: Option<String>,
+    audience: Option<String>,
+    include_email: bool,
+    fetch_timeout: Option<Duration>,
+    max_response_size: Option<usize>,
 }
 
 impl<'a> Default for Builder<'a> {
     fn default() -> Self {
         Self {
-            scopes: &["https://www.googleapis.com/auth/cloud-platform"],
-            source: Default::default(),
+            scopes: vec![CLOUD_PLATFORM.into()],
+            sources: vec![Default::default()],
+            metadata_timeout: None,
+            metadata_host: None,
+            metadata_default_scopes: false,
+            disable_metadata: false,
+            strict_api_key: false,
+            validate_scopes: false,
+            metadata_client: None,
+            token_uri: None,
+            user_email: false,
+            target_principal: None,
+            audience: None,
+            include_email: false,
+            fetch_timeout: None,
+            max_response_size: None,
         }
     }
 }
@@ -100,50 +659,574 @@ impl<'a> Builder<'a> {
 
     #[must_use]
     pub fn no_credentials(mut self) -> Self {
-        self.source = Source::None;
+        self.sources = vec![Source::None];
         self
     }
 
     #[must_use]
     pub fn api_key(mut self, key: impl Into<String>) -> Self {
-        self.source = Source::ApiKey { key: key.into() };
+        self.sources = vec![Source::ApiKey { key: key.into() }];
+        self
+    }
+
+    /// Rejects API keys that do not look like Google API keys, i.e. `AIza` followed by 35 URL-safe
+    /// characters, with [`Error::UnrecognizedApiKey`]. This catches keys pasted by mistake, e.g.
+    /// OAuth 2.0 client secrets, but may reject key formats Google introduces in the future.
+    #[must_use]
+    pub fn strict_api_key(mut self) -> Self {
+        self.strict_api_key = true;
         self
     }
 
     #[must_use]
     pub fn json<'b: 'a>(mut self, data: &'b [u8]) -> Self {
-        self.source = Source::Json { data };
+        self.sources = vec![Source::Json { data }];
         self
     }
 
     #[must_use]
     pub fn json_file<'b: 'a>(mut self, path: &'b Path) -> Self {
-        self.source = Source::JsonFile { path };
+        self.sources = vec![Source::JsonFile { path }];
         self
     }
 
+    /// Uses the metadata server with `account`, the email or the numeric unique id of a service
+    /// account attached to the instance, or the default service account if it is `None`.
+    /// Other values fail with [`Error::MetadataAccount`].
     #[must_use]
     pub fn metadata(mut self, account: impl Into<Option<String>>) -> Self {
-        self.source = Source::Metadata { account: account.into() };
+        self.sources = vec![Source::Metadata { account: account.into() }];
+        self
+    }
+
+    /// Uses the access token of the active `gcloud` session, obtained by running
+    /// `gcloud auth print-access-token`. The configured scopes are ignored.
+    #[must_use]
+    pub fn gcloud_cli(mut self) -> Self {
+        self.sources = vec![Source::GcloudCli];
+        self
+    }
+
+    /// Uses a custom [`Fetcher`] to fetch tokens, e.g. [`MockFetcher`](crate::MockFetcher) in tests.
+    #[must_use]
+    pub fn fetcher(mut self, fetcher: impl Fetcher) -> Self {
+        self.sources = vec![Source::Fetcher(Box::new(fetcher))];
+        self
+    }
+
+    /// Exchanges a subject token for access tokens at a security token service.
+    #[must_use]
+    pub fn token_exchange(mut self, token_exchange: TokenExchange) -> Self {
+        self.sources = vec![Source::TokenExchange(token_exchange)];
+        self
+    }
+
+    /// Fetches tokens with the OAuth 2.0 client credentials grant. This source is never used by
+    /// the default resolution.
+    #[must_use]
+    pub fn client_credentials(mut self, client_credentials: ClientCredentials) -> Self {
+        self.sources = vec![Source::ClientCredentials(client_credentials)];
+        self
+    }
+
+    /// Reads the JSON of user or service account credentials from the Secret Manager secret
+    /// version `name`, e.g. `projects/p/secrets/s/versions/latest`. The secret is accessed with the
+    /// default credentials, e.g. those of the metadata server, with the `cloud-platform` scope.
+    /// Failing to access it is reported with [`Error::SecretManager`].
+    #[must_use]
+    pub fn secret_manager(mut self, name: impl Into<String>) -> Self {
+        self.sources = vec![Source::SecretManager { name: name.into() }];
+        self
+    }
+
+    /// Tries each source in order and uses the first one that yields credentials.
+    /// If all of them fail, [`Error::Sources`] reports why each source failed.
+    #[must_use]
+    pub fn sources(mut self, sources: impl IntoIterator<Item = Source<'a>>) -> Self {
+        self.sources = sources.into_iter().collect();
+        self
+    }
+
+    /// Sets the timeout for checking if this process is running on GCE and for fetching tokens
+    /// from the metadata server, so that resolving credentials fails fast on non-GCE hosts.
+    #[must_use]
+    pub fn metadata_timeout(mut self, timeout: Duration) -> Self {
+        self.metadata_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the host of the metadata server, e.g. `127.0.0.1:8080` or `http://metadata-proxy:8080`,
+    /// for environments that serve the metadata through a proxy or an emulator.
+    /// Like the `GCE_METADATA_HOST` environment variable, the host is assumed to be reachable, so
+    /// the check whether this process is running on GCE is skipped.
+    #[must_use]
+    pub fn metadata_host(mut self, host: impl Into<String>) -> Self {
+        self.metadata_host = Some(host.into());
+        self
+    }
+
+    /// Uses `client` to access the metadata server, so that its connections and the cached result
+    /// of the check whether this process is running on GCE are shared with the rest of the
+    /// application, e.g. on GKE where many subsystems access the metadata server.
+    #[must_use]
+    pub fn metadata_client(mut self, client: gcemeta::Client<HttpConnector>) -> Self {
+        self.metadata_client = Some(client);
+        self
+    }
+
+    /// Does not request scopes from the metadata server, so that tokens have the scopes the VM is
+    /// configured with. Requesting scopes the VM lacks, e.g. the default `cloud-platform`, fails.
+    #[must_use]
+    pub fn metadata_default_scopes(mut self) -> Self {
+        self.metadata_default_scopes = true;
+        self
+    }
+
+    /// Does not look for credentials from the metadata server in the default resolution, so that
+    /// it fails fast with [`Error::CredentialsSource`] on hosts known not to be on GCE, e.g. local
+    /// machines and CI. Setting the `NO_GCE_CHECK` environment variable to `true` has the same
+    /// effect. The [`metadata`](Self::metadata) source is not affected.
+    #[must_use]
+    pub fn disable_metadata(mut self) -> Self {
+        self.disable_metadata = true;
+        self
+    }
+
+    /// Sets the scopes of tokens, e.g. `&["https://www.googleapis.com/auth/pubsub"]` or scopes
+    /// read from a configuration file as `Vec<String>`.
+    ///
+    /// User and service account credentials need at least one scope, otherwise building them fails
+    /// with [`Error::EmptyScopes`]. The metadata server uses the scopes the VM is configured with.
+    #[must_use]
+    pub fn scopes<I>(mut self, scopes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.scopes = scopes.into_iter().map(|s| s.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// Rejects scopes that are not `https` URLs, except the OpenID Connect scopes `openid`,
+    /// `email` and `profile`, with [`Error::InvalidScope`] when building the credentials. This
+    /// catches typos such as `cloud-platform` instead of
+    /// `https://www.googleapis.com/auth/cloud-platform`, which otherwise fail only when a token is
+    /// fetched.
+    #[must_use]
+    pub fn validate_scopes(mut self) -> Self {
+        self.validate_scopes = true;
+        self
+    }
+
+    /// Sends the token requests of service account credentials to `token_uri`, e.g. a regional
+    /// token endpoint or a local server in tests, instead of the `token_uri` of the JSON key.
+    /// The endpoint is also the audience of the JWT assertion. Other credentials are not affected.
+    #[must_use]
+    pub fn token_uri(mut self, token_uri: impl Into<String>) -> Self {
+        self.token_uri = Some(token_uri.into());
+        self
+    }
+
+    /// Finds the email of the account that authorized user credentials, so that
+    /// [`Credentials::describe`] tells who the process is running as, e.g. in shared development
+    /// environments. The `userinfo.email` scope is added to the scopes, and building the credentials
+    /// fetches a token and calls the userinfo endpoint once. The email is left unknown if either
+    /// call fails. Other credentials are not affected.
+    #[must_use]
+    pub fn user_email(mut self) -> Self {
+        self.user_email = true;
+        self
+    }
+
+    /// Impersonates the service account `target_principal`, e.g.
+    /// `sa@project.iam.gserviceaccount.com`, using the credentials found in the sources.
+    /// Those credentials need the `roles/iam.serviceAccountTokenCreator` role on the target.
+    #[must_use]
+    pub fn impersonate(mut self, target_principal: impl Into<String>) -> Self {
+        self.target_principal = Some(target_principal.into());
+        self
+    }
+
+    /// Uses ID tokens for `audience` issued to the impersonated service account instead of
+    /// access tokens, e.g. to call Cloud Run services. The scopes are ignored.
+    /// This has no effect unless [`impersonate`](Self::impersonate) is also set.
+    #[must_use]
+    pub fn id_token(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Includes the `email` and `email_verified` claims in ID tokens,
+    /// which some audiences require.
+    #[must_use]
+    pub fn include_email(mut self, include_email: bool) -> Self {
+        self.include_email = include_email;
+        self
+    }
+
+    /// Sets the timeout of the requests sent while building the credentials, i.e. accessing the
+    /// [`secret_manager`](Self::secret_manager) secret, including fetching the token of the
+    /// default credentials, and fetching the [`user_email`](Self::user_email). A timeout fails the
+    /// build with [`Error::SecretManager`] caused by
+    /// [`AuthError::Timeout`](crate::AuthError::Timeout), or leaves the email unknown.
+    #[must_use]
+    pub fn fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.fetch_timeout = Some(timeout);
         self
     }
 
+    /// Sets the maximum size of the response bodies read while building the credentials.
+    /// The default is 64 KiB.
     #[must_use]
-    pub fn scopes(mut self, scopes: &'static [&'static str]) -> Self {
-        self.scopes = scopes;
+    pub fn max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = Some(max_response_size);
         self
     }
 
-    pub async fn build(self) -> Result<Credentials> {
-        match self.source {
+    pub async fn build(mut self) -> Result<Credentials> {
+        if self.validate_scopes {
+            impls::check_scope_urls(&self.scopes)?;
+        }
+        let target_principal = match self.target_principal.take() {
+            Some(target_principal) => target_principal,
+            None => return self.build_source().await,
+        };
+        // The source credentials only call the IAM Credentials API.
+        let scopes = std::mem::replace(&mut self.scopes, vec![CLOUD_PLATFORM.into()]);
+        let id_token = self
+            .audience
+            .take()
+            .map(|audience| IdToken { audience, include_email: self.include_email });
+        let source = self.build_source().await?;
+        impls::impersonate(source, target_principal, scopes, id_token)
+    }
+
+    async fn build_source(mut self) -> Result<Credentials> {
+        let token_uri = self.token_uri.take();
+        let user_email = self.user_email;
+        let config = self.fetch_config();
+        let mut credentials = self.find_source().await?;
+        if let Some(token_uri) = token_uri {
+            credentials = impls::override_token_uri(credentials, token_uri)?;
+        }
+        impls::find_project_id(&mut credentials).await;
+        if user_email {
+            impls::find_user_email(&mut credentials, &config).await;
+        }
+        Ok(credentials)
+    }
+
+    async fn find_source(mut self) -> Result<Credentials> {
+        if self.sources.len() == 1 {
+            let source = self.sources.pop().unwrap();
+            return self.resolve(source).await;
+        }
+
+        let mut errors = Vec::with_capacity(self.sources.len());
+        for source in std::mem::take(&mut self.sources) {
+            let name = format!("{:?}", source);
+            match self.resolve(source).await {
+                Ok(credentials) => return Ok(credentials),
+                Err(err) => {
+                    trace!("failed to resolve credentials from {}: {:?}", name, err);
+                    errors.push((name, err));
+                }
+            }
+        }
+        Err(Error::Sources(errors))
+    }
+
+    async fn resolve(&self, source: Source<'a>) -> Result<Credentials> {
+        match source {
             Source::None => Ok(Credentials::None),
-            Source::Default => impls::find_default(self.scopes).await,
-            Source::ApiKey { key } => impls::from_api_key(key),
-            Source::Json { data } => impls::from_json(data, self.scopes),
-            Source::JsonFile { path } => impls::from_json_file(path, self.scopes),
-            Source::Metadata { account } => Ok(impls::from_metadata(account, self.scopes)
+            Source::Default => {
+                let metadata = (!self.disable_metadata).then(|| self.metadata_options());
+                impls::find_default(&self.scopes, metadata).await
+            }
+            Source::ApiKey { key } => impls::from_api_key(key, self.strict_api_key),
+            Source::Json { data } => impls::from_json(data, &self.scopes),
+            Source::JsonFile { path } => impls::from_json_file(path, &self.scopes),
+            Source::Metadata { account } => impls::from_metadata(account, self.metadata_options())
                 .await?
-                .expect("this process must be running on GCE")),
+                .ok_or(Error::CredentialsSource),
+            Source::GcloudCli => Ok(Credentials::GcloudCli),
+            Source::Fetcher(fetcher) => Ok(Credentials::Fetcher(fetcher)),
+            Source::TokenExchange(te) => impls::token_exchange(te, &self.scopes),
+            Source::ClientCredentials(cc) => impls::client_credentials(cc, &self.scopes),
+            Source::SecretManager { name } => {
+                impls::check_secret_name(&name)?;
+                let scopes = [CLOUD_PLATFORM.to_owned()];
+                let metadata = (!self.disable_metadata).then(|| impls::MetadataOptions {
+                    scopes: scopes.to_vec(),
+                    ..self.metadata_options()
+                });
+                let bootstrap = impls::find_default(&scopes, metadata).await?;
+                impls::from_secret_manager(bootstrap, name, &self.scopes, &self.fetch_config())
+                    .await
+            }
         }
     }
+
+    fn metadata_options(&self) -> impls::MetadataOptions {
+        impls::MetadataOptions {
+            client: self.metadata_client.clone(),
+            scopes: self.metadata_scopes(),
+            timeout: self.metadata_timeout,
+            host: self.metadata_host.clone(),
+        }
+    }
+
+    // The options of the clients that send requests while building the credentials.
+    fn fetch_config(&self) -> auth::Config {
+        let mut config = auth::Config { fetch_timeout: self.fetch_timeout, ..Default::default() };
+        if let Some(max_response_size) = self.max_response_size {
+            config.max_response_size = max_response_size;
+        }
+        config
+    }
+
+    fn metadata_scopes(&self) -> Vec<String> {
+        if self.metadata_default_scopes {
+            Vec::new()
+        } else {
+            self.scopes.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(Credentials::ApiKey("api-key".into()).describe(), CredentialsInfo {
+            kind: CredentialsKind::ApiKey,
+            account: None,
+            key_id: None,
+            scopes: vec![],
+        });
+
+        let sa = Credentials::ServiceAccount(ServiceAccount {
+            scopes: vec!["scope1".into(), "scope2".into()],
+            source: SourceKind::Json,
+            path: None,
+            token_uri_override: None,
+            project_id: None,
+            client_email: "sa@example.com".into(),
+            private_key_id: "key-id".into(),
+            private_key: impls::encoding_key(impls::TEST_PRIVATE_KEY).unwrap(),
+            private_key_path: None,
+            token_uri: "https://oauth2.googleapis.com/token".into(),
+            universe_domain: DEFAULT_UNIVERSE_DOMAIN.into(),
+        });
+        let info = sa.describe();
+        assert_eq!(info, CredentialsInfo {
+            kind: CredentialsKind::ServiceAccount,
+            account: Some("sa@example.com".into()),
+            key_id: Some("key-id".into()),
+            scopes: vec!["scope1".into(), "scope2".into()],
+        });
+        assert!(!serde_json::to_string(&info).unwrap().contains("private-key"));
+        let debug = format!("{:?}", sa);
+        assert!(debug.contains("sa@example.com") && debug.contains("scope1"));
+        assert!(!debug.contains("private-key"));
+        assert_eq!(format!("{:?}", Credentials::ApiKey("api-key".into())), r#"ApiKey("***")"#);
+        assert!(!serde_json::to_string(&sa).unwrap().contains("private-key"));
+        assert!(sa.same_identity(&sa));
+        assert!(!sa.same_identity(&Credentials::None));
+    }
+
+    #[tokio::test]
+    async fn test_user_email() {
+        // The token endpoint is unreachable, so the email is left unknown.
+        let json = br#"{
+"client_id": "client-id",
+"client_secret": "secret",
+"refresh_token": "refresh",
+"token_uri": "http://127.0.0.1:1/token",
+"type": "authorized_user"
+}"#;
+        let mut credentials =
+            Credentials::builder().json(json).scopes(["scope"]).user_email().build().await.unwrap();
+        let info = credentials.describe();
+        assert_eq!(info.scopes, vec![
+            "scope".to_owned(),
+            crate::auth::USERINFO_EMAIL_SCOPE.to_owned()
+        ]);
+        assert_eq!(info.account, None);
+        assert_eq!(credentials.scopes(), info.scopes);
+        assert!(Credentials::ApiKey("api-key".into()).scopes().is_empty());
+
+        if let Credentials::User(ref mut user) = credentials {
+            user.email = Some("user@example.com".into());
+        }
+        assert_eq!(credentials.describe().account.as_deref(), Some("user@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_sources() {
+        let credentials = Credentials::builder()
+            .sources(vec![Source::Json { data: b"{}" }, Source::ApiKey { key: "api-key".into() }])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(credentials, Credentials::ApiKey("api-key".into()));
+        assert_eq!(credentials.source_kind(), SourceKind::ApiKey);
+
+        let err = Credentials::builder()
+            .sources(vec![Source::Json { data: b"{}" }, Source::JsonFile {
+                path: Path::new("/not/found.json"),
+            }])
+            .build()
+            .await
+            .unwrap_err();
+        match err {
+            Error::Sources(errors) => {
+                assert_eq!(errors.len(), 2);
+                assert_eq!(errors[0].0, "Json");
+                assert!(matches!(errors[0].1, Error::CredentialsFormat { .. }));
+                assert_eq!(errors[1].0, r#"JsonFile { path: "/not/found.json" }"#);
+                assert!(matches!(errors[1].1, Error::CredentialsFile { .. }));
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_project_id() {
+        let json = format!(
+            r#"{{
+"type": "service_account",
+"project_id": "project",
+"private_key_id": "[KEY-ID]",
+"private_key": {:?},
+"client_email": "[SERVICE-ACCOUNT-EMAIL]"
+}}"#,
+            impls::TEST_PRIVATE_KEY
+        );
+        let json = json.as_bytes();
+        let credentials = Credentials::builder().json(json).build().await.unwrap();
+        assert_eq!(credentials.project_id(), Some("project"));
+
+        let credentials =
+            Credentials::builder().json(json).impersonate("sa@example.com").build().await.unwrap();
+        assert_eq!(credentials.project_id(), Some("project"));
+
+        assert_eq!(Credentials::ApiKey("api-key".into()).project_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_token_exchange() {
+        let te = TokenExchange::new(
+            "https://sts.googleapis.com/v1/token",
+            "subject-token",
+            "urn:ietf:params:oauth:token-type:jwt",
+        )
+        .audience("audience");
+        assert!(!format!("{:?}", te).contains("subject-token"));
+
+        let credentials =
+            Credentials::builder().token_exchange(te).scopes(&["scope1"]).build().await.unwrap();
+        assert_eq!(credentials.describe().kind, CredentialsKind::TokenExchange);
+        assert_eq!(credentials.describe().scopes, vec!["scope1".to_owned()]);
+        assert_eq!(credentials.scopes(), ["scope1".to_owned()]);
+        assert_eq!(credentials.source_kind(), SourceKind::TokenExchange);
+
+        let te = TokenExchange::new("^", "subject-token", "urn:ietf:params:oauth:token-type:jwt");
+        let err = Credentials::builder().token_exchange(te).build().await.unwrap_err();
+        assert!(matches!(err, Error::TokenUriFormat(_)));
+    }
+
+    #[tokio::test]
+    async fn test_client_credentials() {
+        let cc = ClientCredentials::new("https://idp.example.com/token", "client-id", "secret");
+        assert!(!format!("{:?}", cc).contains("secret"));
+
+        let credentials = Credentials::builder()
+            .client_credentials(cc)
+            .scopes(&["scope1"])
+            .build()
+            .await
+            .unwrap();
+        let info = credentials.describe();
+        assert_eq!(info.kind, CredentialsKind::ClientCredentials);
+        assert_eq!(info.key_id.as_deref(), Some("client-id"));
+        assert_eq!(credentials.scopes(), ["scope1".to_owned()]);
+        assert_eq!(credentials.source_kind(), SourceKind::ClientCredentials);
+
+        let cc = ClientCredentials::new("^", "client-id", "secret");
+        let err = Credentials::builder().client_credentials(cc).build().await.unwrap_err();
+        assert!(matches!(err, Error::TokenUriFormat(_)));
+    }
+
+    #[tokio::test]
+    async fn test_metadata_host() {
+        let credentials =
+            Credentials::builder().metadata(None).metadata_host("127.0.0.1:8080").build().await;
+        let credentials = credentials.unwrap();
+        match credentials {
+            Credentials::Metadata(ref meta) => {
+                assert_eq!(meta.host, Some(Uri::from_static("127.0.0.1:8080")));
+                assert_eq!(credentials.source_kind(), SourceKind::Metadata);
+            }
+            credentials => panic!("unexpected credentials: {:?}", credentials),
+        }
+
+        let credentials = Credentials::builder()
+            .metadata(None)
+            .metadata_host("127.0.0.1:8080")
+            .metadata_default_scopes()
+            .build()
+            .await;
+        match credentials.unwrap() {
+            Credentials::Metadata(meta) => assert!(meta.scopes.is_empty()),
+            credentials => panic!("unexpected credentials: {:?}", credentials),
+        }
+
+        let credentials = Credentials::builder().metadata(None).metadata_host("^").build().await;
+        assert!(matches!(credentials, Err(Error::Gcemeta(_))));
+    }
+
+    #[tokio::test]
+    async fn test_impersonate() {
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .scopes(&["scope1"])
+            .impersonate("sa@example.com")
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(credentials.describe(), CredentialsInfo {
+            kind: CredentialsKind::Impersonated,
+            account: Some("sa@example.com".into()),
+            key_id: None,
+            scopes: vec!["scope1".into()],
+        });
+        assert_eq!(credentials.source_kind(), SourceKind::Fetcher);
+
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .impersonate("sa@example.com")
+            .id_token("https://example.com")
+            .include_email(true)
+            .build()
+            .await
+            .unwrap();
+        match credentials {
+            Credentials::Impersonated(imp) => assert_eq!(
+                imp.id_token,
+                Some(IdToken { audience: "https://example.com".into(), include_email: true })
+            ),
+            credentials => panic!("unexpected credentials: {:?}", credentials),
+        }
+
+        let err = Credentials::builder()
+            .api_key("api-key")
+            .impersonate("sa@example.com")
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Impersonation(_)));
+    }
 }
@@ -0,0 +1,128 @@
+use std::{
+    fmt,
+    task::{Context, Poll},
+};
+
+use futures_util::task::noop_waker_ref;
+use tokio::{runtime::Handle, task::JoinHandle};
+use tonic::{metadata::MetadataMap, service::Interceptor, Request, Status};
+use tracing::warn;
+
+use crate::{auth, authorizer::Authorizer};
+
+/// Adds credentials to each gRPC call as a tonic [`Interceptor`], e.g. with
+/// `PublisherClient::with_interceptor(channel, authorizer.interceptor())`, as an alternative to
+/// wrapping the whole channel with [`GoogleAuthz`](crate::GoogleAuthz).
+///
+/// Interceptors are synchronous, so the cached token is read and a new one is fetched in the
+/// background on the current tokio runtime when it expires. Calls are rejected with
+/// `UNAVAILABLE` until the first token is fetched, unless it is fetched beforehand with
+/// [`Authorizer::authorization_header`]. The token is shared with the [`Authorizer`].
+pub struct AuthInterceptor {
+    auth: auth::Auth,
+    // The task driving the fetch of a new token.
+    refresh: Option<JoinHandle<()>>,
+}
+
+impl AuthInterceptor {
+    pub(crate) fn new(auth: auth::Auth) -> Self {
+        Self { auth, refresh: None }
+    }
+
+    // Fetches a new token in the background, unless it is already being fetched. The fetch is
+    // shared with the handle of this interceptor.
+    fn spawn_refresh(&mut self) -> Result<(), Status> {
+        if matches!(self.refresh, Some(ref task) if !task.is_finished()) {
+            return Ok(());
+        }
+        let handle = Handle::try_current().map_err(|_| {
+            Status::unavailable("token is not fetched and there is no tokio runtime to fetch it")
+        })?;
+        let mut auth = self.auth.clone();
+        self.refresh = Some(handle.spawn(async move {
+            if let Err(err) = auth.token().await {
+                warn!("failed to fetch token in the background: err={:?}", err);
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        match self.auth.poll_ready(&mut Context::from_waker(noop_waker_ref())) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Err(status(err)),
+            Poll::Pending => {
+                self.spawn_refresh()?;
+                // The last token is used until it actually expires, like `poll_ready` does when
+                // it serves stale tokens.
+                if !matches!(self.auth.token_ttl(), Some(ttl) if !ttl.is_zero()) {
+                    return Err(Status::unavailable("token is being fetched"));
+                }
+            }
+        }
+
+        let mut headers = std::mem::take(req.metadata_mut()).into_headers();
+        self.auth.add_headers(&mut headers).map_err(status)?;
+        *req.metadata_mut() = MetadataMap::from_headers(headers);
+        Ok(req)
+    }
+}
+
+impl Clone for AuthInterceptor {
+    fn clone(&self) -> Self {
+        Self::new(self.auth.clone())
+    }
+}
+
+impl fmt::Debug for AuthInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthInterceptor").field("auth", &self.auth).finish()
+    }
+}
+
+fn status(err: auth::Error) -> Status {
+    if err.is_retryable() {
+        Status::unavailable(err.to_string())
+    } else {
+        Status::unauthenticated(err.to_string())
+    }
+}
+
+impl Authorizer {
+    /// Returns a tonic interceptor that adds the credentials to gRPC calls.
+    pub fn interceptor(&self) -> AuthInterceptor {
+        AuthInterceptor::new(self.auth.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use hyper::header::AUTHORIZATION;
+
+    use super::*;
+    use crate::{Credentials, MockFetcher};
+
+    #[tokio::test]
+    async fn test_interceptor() {
+        let credentials = Credentials::builder()
+            .fetcher(MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let authorizer = Authorizer::builder().credentials(credentials).build_authorizer().await;
+
+        let mut interceptor = authorizer.interceptor();
+        let req = interceptor.call(Request::new(())).unwrap();
+        assert_eq!(req.metadata().get(AUTHORIZATION.as_str()).unwrap(), "Bearer token");
+        assert!(authorizer.token_ttl().is_some());
+
+        let credentials = Credentials::ApiKey("api-key".into());
+        let authorizer = Authorizer::builder().credentials(credentials).build_authorizer().await;
+        let req = authorizer.interceptor().call(Request::new(())).unwrap();
+        assert_eq!(req.metadata().get("x-goog-api-key").unwrap(), "api-key");
+    }
+}
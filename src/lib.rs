@@ -1,8 +1,31 @@
+#![allow(clippy::result_large_err)]
+
+#[cfg(all(feature = "openssl", any(feature = "native-certs", feature = "webpki-roots")))]
+compile_error!(
+    "feature `openssl` cannot be enabled with `native-certs` or `webpki-roots`; \
+     use `default-features = false`"
+);
+#[cfg(not(any(feature = "openssl", feature = "native-certs", feature = "webpki-roots")))]
+compile_error!("one of the features `native-certs`, `webpki-roots` or `openssl` must be enabled");
+
 mod auth;
+mod authorizer;
 mod credentials;
+#[cfg(feature = "tonic")]
+mod interceptor;
 mod service;
-mod sync;
 
-pub use auth::Error as AuthError;
-pub use credentials::{Credentials, Error as CredentialsError};
-pub use service::{Error, GoogleAuthz};
+#[cfg(not(feature = "openssl"))]
+pub use auth::tls_roots;
+pub use auth::{
+    AccessToken, AddressFamily, Error as AuthError, Fetcher, HttpVersion, Metrics, MockFetcher,
+    RefreshThreshold, ResponseFuture, RootStore, ScopeDelimiter, TlsRoots, TokenResponse,
+};
+pub use authorizer::Authorizer;
+pub use credentials::{
+    ClientCredentials, Credentials, CredentialsInfo, CredentialsKind, Error as CredentialsError,
+    Source as CredentialsSource, TokenExchange,
+};
+#[cfg(feature = "tonic")]
+pub use interceptor::AuthInterceptor;
+pub use service::{Error, GoogleAuthz, IdTokenPerHost, RetryOnUnauthorized};
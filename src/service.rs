@@ -1,27 +1,43 @@
 use std::{
     fmt,
     future::{self, Ready},
+    net::IpAddr,
+    sync::Arc,
     task::{self, Poll},
+    time::Duration,
 };
 
 use futures_util::{
-    future::{Either, MapErr},
+    future::{BoxFuture, Either, MapErr},
     TryFutureExt as _,
 };
-use hyper::Request;
+use hyper::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, HOST},
+    http::response::Parts,
+    Request, Response, StatusCode,
+};
+use tracing::warn;
 
 use crate::{
-    auth::{self, Auth, Config},
+    auth::{
+        self, AccessToken, AddressFamily, Auth, Config, HttpVersion, Metrics, RefreshThreshold,
+        ResponseHook, MAX_JWT_LIFETIME,
+    },
+    authorizer::Authorizer,
     credentials::Credentials,
 };
 
 /// Represents an inner service error or Google authentication error.
+///
+/// It implements `std::error::Error` if the service error does, e.g. `hyper::Error` or
+/// `tonic::transport::Error`, and [`source`](std::error::Error::source) returns the wrapped error,
+/// so that frameworks such as `anyhow` report the whole chain.
 #[derive(thiserror::Error, Debug)]
 pub enum Error<E> {
-    #[error("inner service error: {0}")]
-    Service(E),
-    #[error("google authentication error: {0}")]
-    GoogleAuthz(auth::Error),
+    #[error("inner service error")]
+    Service(#[source] E),
+    #[error("google authentication error")]
+    GoogleAuthz(#[from] auth::Error),
 }
 
 pub struct Builder<S> {
@@ -37,59 +53,487 @@ impl Builder<()> {
 }
 
 impl<S> Builder<S> {
+    /// Rejects requests whose URI scheme is not `https`, whatever the credentials are, so that
+    /// tokens and API keys are never sent in plain text. It is enabled by default.
+    ///
+    /// Disable it for tonic channels, whose requests have no scheme until the channel adds the
+    /// origin. The channel itself should connect with TLS.
     #[must_use]
-    #[cfg(not(feature = "tonic"))]
     pub fn enforce_https(mut self, enforce_https: bool) -> Self {
         self.config.enforce_https = enforce_https;
         self
     }
 
+    /// Sends the API key in the `X-Goog-Api-Key` header instead of the `key` query parameter,
+    /// so that the key is not recorded in access logs and proxies that log URLs.
+    #[must_use]
+    pub fn api_key_in_header(mut self, api_key_in_header: bool) -> Self {
+        self.config.api_key_in_header = api_key_in_header;
+        self
+    }
+
     #[must_use]
     pub fn max_retry(mut self, max_retry: u8) -> Self {
         self.config.max_retry = max_retry;
         self
     }
 
+    /// Sets the level at which a token fetch that fails for good is logged, i.e. after the
+    /// retries run out or on an error that is not retried. Failed attempts that are retried are
+    /// logged at `INFO`. The default is `ERROR`.
     #[must_use]
-    pub fn credentials(mut self, credentials: impl Into<Option<Credentials>>) -> Self {
-        self.credentials = credentials.into();
+    pub fn fetch_failure_level(mut self, level: tracing::Level) -> Self {
+        self.config.fetch_failure_level = level;
+        self
+    }
+
+    /// Sets how many times a request to the metadata server is retried when the server answers
+    /// `500` or `503`, which it does for a moment under load or during maintenance. Retries back
+    /// off from 100 milliseconds, doubling each time. The default is 3; `0` disables them.
+    #[must_use]
+    pub fn metadata_retry(mut self, metadata_retry: u8) -> Self {
+        self.config.metadata_retry = metadata_retry;
+        self
+    }
+
+    #[must_use]
+    pub fn fetch_timeout(mut self, fetch_timeout: Duration) -> Self {
+        self.config.fetch_timeout = Some(fetch_timeout);
+        self
+    }
+
+    /// Sets the maximum size of token response bodies. A larger response fails the fetch.
+    /// The default is 64 KiB.
+    #[must_use]
+    pub fn max_response_size(mut self, max_response_size: usize) -> Self {
+        self.config.max_response_size = max_response_size;
+        self
+    }
+
+    /// Sets when a cached token is refreshed before it expires. The default refreshes a token
+    /// 10 seconds before it expires. A fraction out of the range `0.0..=1.0` is clamped.
+    #[must_use]
+    pub fn refresh_threshold(mut self, refresh_threshold: RefreshThreshold) -> Self {
+        self.config.refresh_threshold = match refresh_threshold {
+            RefreshThreshold::Fraction(fraction) if !(0.0..=1.0).contains(&fraction) => {
+                warn!("refresh threshold fraction is out of range: {}", fraction);
+                RefreshThreshold::Fraction(if fraction > 1.0 { 1.0 } else { 0.0 })
+            }
+            threshold => threshold,
+        };
+        self
+    }
+
+    /// Brings the expiry of each fetched token forward by a random duration up to
+    /// `expiry_jitter`, so that many instances started at the same time do not refresh their
+    /// tokens at the same time. The jitter is at most half of the token lifetime.
+    #[must_use]
+    pub fn expiry_jitter(mut self, expiry_jitter: Duration) -> Self {
+        self.config.expiry_jitter = expiry_jitter;
+        self
+    }
+
+    /// Keeps serving the last token when refreshing it fails, as long as it expired less than
+    /// `grace` ago, instead of failing the request. The error is logged, and each request tries
+    /// to refresh the token again.
+    ///
+    /// By default, the last token is served until it expires, since it is refreshed before then.
+    #[must_use]
+    pub fn serve_stale_on_refresh_error(mut self, grace: Duration) -> Self {
+        self.config.serve_stale = Some(grace);
+        self
+    }
+
+    /// Does not fetch a token again within `interval` after the last fetch started, even if the
+    /// token looks expired, e.g. because of a severe clock skew. Such requests fail with
+    /// [`AuthError::RefreshRateLimited`](crate::AuthError::RefreshRateLimited) instead of
+    /// hammering the token endpoint. This also delays fetching a token again after an error or a
+    /// [`GoogleAuthz::invalidate_token`].
+    #[must_use]
+    pub fn min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.min_refresh_interval = Some(interval);
+        self
+    }
+
+    /// Sets the application identifier that is prepended to the User-Agent of token requests.
+    #[must_use]
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Bills the requests to `project` with the `X-Goog-User-Project` header, e.g. when the
+    /// service account lives in a different project than the one that pays for quota. The caller
+    /// needs the `serviceusage.services.use` permission on the project.
+    #[must_use]
+    pub fn quota_project(mut self, project: impl Into<String>) -> Self {
+        self.config.quota_project = Some(project.into());
+        self
+    }
+
+    #[must_use]
+    pub fn http_version(mut self, http_version: HttpVersion) -> Self {
+        self.config.http_version = http_version;
+        self
+    }
+
+    /// Adds `headers`, e.g. `X-Goog-Request-Reason` or routing headers required by a gateway, to
+    /// every authorized request, replacing the values the request already has. Credential headers,
+    /// i.e. `Authorization` and `X-Goog-Api-Key`, are always set by the credentials, and
+    /// [`AuthError::ReservedHeader`](crate::AuthError::ReservedHeader) is returned if any of them
+    /// is supplied.
+    pub fn extra_headers(mut self, headers: HeaderMap) -> Result<Self, auth::Error> {
+        for name in [AUTHORIZATION.as_str(), auth::api_key::X_GOOG_API_KEY] {
+            if headers.contains_key(name) {
+                return Err(auth::Error::ReservedHeader(name.to_owned()));
+            }
+        }
+        self.config.extra_headers = headers;
+        Ok(self)
+    }
+
+    /// Sets the TLS configuration of the client that fetches tokens, e.g. with the roots of an
+    /// internal CA or a client certificate. The root certificates selected by the `native-certs`
+    /// or `webpki-roots` feature are not used then. ALPN protocols are set by
+    /// [`http_version`](Self::http_version). It is not used with a `reqwest::Client`.
+    /// It is not available with the `openssl` feature.
+    #[cfg(not(feature = "openssl"))]
+    #[must_use]
+    pub fn tls_config(mut self, tls_config: rustls::ClientConfig) -> Self {
+        self.config.tls_config = Some(auth::TlsConfig(tls_config));
+        self
+    }
+
+    /// Sends token requests from `local_address`, e.g. on multi-homed hosts whose firewall allows
+    /// only a specific source address. Requests to the metadata server are not affected, and it is
+    /// not used with a `reqwest::Client`.
+    #[must_use]
+    pub fn local_address(mut self, local_address: IpAddr) -> Self {
+        self.config.local_address = Some(local_address);
+        self
+    }
+
+    /// Connects to token endpoints only over IPv4 or IPv6, e.g. on dual-stack hosts where one of
+    /// them is broken and token fetches hang on it. Both are used by default, falling back to the
+    /// other family if the first one does not connect within 300 ms. A
+    /// [`local_address`](Self::local_address) takes precedence. It is not used with a
+    /// `reqwest::Client`.
+    #[must_use]
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.config.address_family = address_family;
+        self
+    }
+
+    /// Closes connections to token endpoints that have been idle for `timeout`, 90 seconds by
+    /// default, e.g. to trim them in scale-to-zero environments. It is not used with a
+    /// `reqwest::Client`.
+    #[must_use]
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.pool_idle_timeout = Some(timeout);
         self
     }
 
-    pub async fn build<B>(self) -> GoogleAuthz<S>
+    /// Keeps at most `max_idle` idle connections per token endpoint, unlimited by default.
+    /// `0` disables connection reuse. It is not used with a `reqwest::Client`.
+    #[must_use]
+    pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> Self {
+        self.config.pool_max_idle_per_host = Some(max_idle);
+        self
+    }
+
+    /// Sets the lifetime of the JWT assertion used by service account credentials.
+    /// Values over one hour are clamped to one hour, the maximum allowed by Google.
+    #[must_use]
+    pub fn jwt_lifetime(mut self, jwt_lifetime: Duration) -> Self {
+        self.config.jwt_lifetime = if jwt_lifetime > MAX_JWT_LIFETIME {
+            warn!("jwt lifetime is clamped to {:?}: {:?}", MAX_JWT_LIFETIME, jwt_lifetime);
+            MAX_JWT_LIFETIME
+        } else {
+            jwt_lifetime
+        };
+        self
+    }
+
+    /// Uses self-signed JWTs whose `aud` is `audience`, e.g. `https://pubsub.googleapis.com/`,
+    /// as tokens of service account credentials instead of exchanging them at the token endpoint.
+    /// Only the API of `audience` accepts these tokens, and the scopes are not included.
+    #[must_use]
+    pub fn jwt_audience(mut self, audience: impl Into<String>) -> Self {
+        self.config.jwt_audiences = vec![audience.into()];
+        self
+    }
+
+    /// Same as [`jwt_audience`](Self::jwt_audience), but the `aud` claim is an array of
+    /// `audiences` if there are more than one, for OIDC consumers that accept several audiences.
+    /// An empty list disables self-signed JWTs. ID tokens fetched from the IAM Credentials API or
+    /// the metadata server always have a single audience.
+    #[must_use]
+    pub fn jwt_audiences<I>(mut self, audiences: I) -> Self
     where
-        S: tower_service::Service<Request<B>>,
+        I: IntoIterator,
+        I::Item: Into<String>,
     {
-        let Builder { config, credentials, service } = self;
-        let credentials = match credentials {
+        self.config.jwt_audiences = audiences.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Adds custom claims to the JWT of service account credentials. The claims set by this
+    /// crate (`iss`, `sub`, `scope`, `aud`, `iat` and `exp`) cannot be overridden, and
+    /// [`AuthError::ReservedClaim`](crate::AuthError::ReservedClaim) is returned if any of them
+    /// is supplied.
+    pub fn extra_jwt_claims(
+        mut self,
+        claims: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, auth::Error> {
+        auth::check_extra_claims(&claims)?;
+        self.config.extra_jwt_claims = claims;
+        Ok(self)
+    }
+
+    /// If enabled, the clock offset against Google's servers is learned from the `Date` header
+    /// when a service account assertion is rejected because of its `iat` or `exp`, and the token
+    /// fetch is retried with the adjusted offset.
+    #[must_use]
+    pub fn sync_clock(mut self, sync_clock: bool) -> Self {
+        self.config.sync_clock = sync_clock;
+        self
+    }
+
+    /// If enabled, each authorized request is logged at the `info` level with a fingerprint of
+    /// its `Authorization` header and its `X-Request-Id` header, if any, so that the token used by
+    /// a failing request can be told apart in logs without exposing it. The request is sent as
+    /// usual.
+    #[must_use]
+    pub fn log_token_fingerprint(mut self, log_token_fingerprint: bool) -> Self {
+        self.config.log_token_fingerprint = log_token_fingerprint;
+        self
+    }
+
+    /// Compares the scopes granted in token responses with the requested ones, and fails token
+    /// fetches with [`AuthError::MissingScopes`](crate::AuthError::MissingScopes) if any is
+    /// missing, e.g. if a user did not consent to all of them, instead of getting 403 responses
+    /// from APIs later. Missing scopes are only logged as warnings by default. Endpoints that do not
+    /// return the granted scopes, e.g. the metadata server, are not checked.
+    #[must_use]
+    pub fn strict_scopes(mut self, strict_scopes: bool) -> Self {
+        self.config.strict_scopes = strict_scopes;
+        self
+    }
+
+    /// Reads the credentials file again when it is modified, e.g. when a key mounted from a secret
+    /// is rotated in place. The file is checked each time a token is fetched. If the modified file
+    /// cannot be read, the old credentials are kept. This applies only to credentials read from a
+    /// file.
+    #[must_use]
+    pub fn watch_credentials(mut self, watch_credentials: bool) -> Self {
+        self.config.watch_credentials = watch_credentials;
+        self
+    }
+
+    /// Fetches tokens with the given `reqwest` client instead of the built-in hyper client,
+    /// so that its proxy, pool, timeout and TLS settings are shared.
+    #[must_use]
+    #[cfg(feature = "reqwest")]
+    pub fn reqwest_client(mut self, client: reqwest::Client) -> Self {
+        self.config.reqwest_client = Some(client);
+        self
+    }
+
+    #[must_use]
+    pub fn metrics(mut self, metrics: impl Metrics) -> Self {
+        self.config.metrics = Arc::new(metrics);
+        self
+    }
+
+    /// Calls `f` with the status and headers of each response of token endpoints, successful or
+    /// not, e.g. to record the rate limit or request id headers Google support asks for when
+    /// debugging quota issues. The body, which has the token, is not passed. Responses of the
+    /// metadata server are not included.
+    #[must_use]
+    pub fn on_fetch_response(mut self, f: impl Fn(&Parts) + Send + Sync + 'static) -> Self {
+        self.config.on_fetch_response = Some(ResponseHook(Arc::new(f)));
+        self
+    }
+
+    #[must_use]
+    pub fn credentials(mut self, credentials: impl Into<Option<Credentials>>) -> Self {
+        self.credentials = credentials.into();
+        self
+    }
+
+    pub async fn build(mut self) -> GoogleAuthz<S> {
+        let credentials = match self.credentials.take() {
             Some(credentials) => credentials,
             None => Credentials::new().await,
         };
-        GoogleAuthz { auth: Auth::new(credentials, config), service }
+        self.build_with_credentials(credentials)
+    }
+
+    /// Builds the service with `credentials` without resolving any, so unlike
+    /// [`build`](Self::build) it needs no executor. Credentials set with
+    /// [`credentials`](Self::credentials) are ignored.
+    pub fn build_with_credentials(self, credentials: Credentials) -> GoogleAuthz<S> {
+        GoogleAuthz::new_with(Auth::new(credentials, self.config), self.service)
+    }
+
+    /// Builds an [`Authorizer`] that authorizes requests without the service.
+    pub async fn build_authorizer(self) -> Authorizer {
+        self.build().await.authorizer()
     }
 }
 
 pub struct GoogleAuthz<S> {
     auth: Auth,
     service: S,
+    // Whether the token is ready for the next call, so that it is not checked again while only
+    // the inner service is pending.
+    auth_ready: bool,
 }
 
 impl GoogleAuthz<()> {
-    pub async fn new<S, B>(service: S) -> GoogleAuthz<S>
-    where
-        S: tower_service::Service<Request<B>>,
-    {
+    pub async fn new<S>(service: S) -> GoogleAuthz<S> {
         Self::builder(service).build().await
     }
 
     pub fn builder<S>(service: S) -> Builder<S> {
         Builder::new(service)
     }
+
+    /// Returns a service that authorizes requests with already resolved `credentials` and the
+    /// default configuration, without an executor. See [`Builder::build_with_credentials`] to
+    /// configure it.
+    pub fn with_credentials<S>(service: S, credentials: Credentials) -> GoogleAuthz<S> {
+        Self::builder(service).build_with_credentials(credentials)
+    }
+
+    /// Returns a service that authorizes requests with `auth`, sharing the token with the other
+    /// services built from it. Unlike [`new`](Self::new), the credentials are not resolved again.
+    pub fn from_auth<S>(auth: Authorizer, service: S) -> GoogleAuthz<S> {
+        GoogleAuthz::new_with(auth.auth, service)
+    }
+}
+
+impl<S> GoogleAuthz<S> {
+    fn new_with(auth: Auth, service: S) -> Self {
+        Self { auth, service, auth_ready: false }
+    }
+
+    /// Returns the current `Authorization` header value, fetching or refreshing the token if
+    /// needed. Returns `None` if the credentials do not use OAuth 2.0 tokens.
+    ///
+    /// This does not touch the inner service, so `()` can be used as the service when only the
+    /// token is needed.
+    pub async fn token(&mut self) -> Result<Option<HeaderValue>, auth::Error> {
+        self.auth.token().await
+    }
+
+    /// Same as [`token`](Self::token), but fails with [`auth::Error::TokenUnsupported`] if the
+    /// credentials do not use OAuth 2.0 tokens, e.g. to put the value into a WebSocket subprotocol
+    /// or the metadata of a gRPC-web proxy instead of the `Authorization` header of a request.
+    pub async fn authorization_header(&mut self) -> Result<HeaderValue, auth::Error> {
+        self.auth.token().await?.ok_or(auth::Error::TokenUnsupported)
+    }
+
+    /// Same as [`token`](Self::token), but returns the token type and the access token separately
+    /// instead of the `Authorization` header value.
+    pub async fn access_token(&mut self) -> Result<Option<AccessToken>, auth::Error> {
+        self.auth.access_token().await
+    }
+
+    /// Fetches the token now instead of on the first request, so that bad credentials or scopes
+    /// fail at startup. It is a no-op if the credentials do not use OAuth 2.0 tokens.
+    pub async fn prefetch(&mut self) -> Result<(), auth::Error> {
+        futures_util::future::poll_fn(|cx| self.auth.poll_ready(cx)).await
+    }
+
+    /// Discards the cached token, e.g. after a downstream API rejected it with 401, so that the
+    /// next `poll_ready` fetches a new one. The token is shared with the clones of this service.
+    pub fn invalidate_token(&self) {
+        self.auth.invalidate_token()
+    }
+
+    /// Replaces the credentials of this service and its clones at runtime, e.g. when an operator
+    /// changes the service account, without building the service again. The clones switch to
+    /// the new credentials on their next `poll_ready`, and until then in-flight requests use the
+    /// old ones. The configuration of the builder is kept, and services derived with
+    /// [`with_scopes`](Self::with_scopes) or [`with_audience`](Self::with_audience) keep their
+    /// scopes or audience.
+    pub fn set_credentials(&self, credentials: Credentials) {
+        self.auth.set_credentials(credentials)
+    }
+
+    /// Returns how long the current token remains valid, or `None` if no token has been fetched
+    /// yet or the credentials do not use OAuth 2.0 tokens.
+    pub fn token_ttl(&self) -> Option<Duration> {
+        self.auth.token_ttl()
+    }
+
+    /// Reports whether requests can be authorized without fetching a token, i.e. a token is
+    /// cached and it is not about to expire per the [`refresh_threshold`](Builder::refresh_threshold).
+    /// Credentials without OAuth 2.0 tokens, e.g. API keys, are always valid. It is `false` after
+    /// [`set_credentials`](Self::set_credentials) until the new credentials are used.
+    pub fn is_token_valid(&self) -> bool {
+        self.auth.is_token_valid()
+    }
+
+    /// Returns an [`Authorizer`] that shares the token with this service.
+    pub fn authorizer(&self) -> Authorizer {
+        Authorizer::from_auth(self.auth.clone())
+    }
+}
+
+impl<S: Clone> GoogleAuthz<S> {
+    /// Returns a service that authorizes requests with tokens for `scopes` instead of the scopes
+    /// of the credentials. Tokens are cached per distinct scope set, so services derived with the
+    /// same scopes share a token.
+    pub fn with_scopes<I>(&self, scopes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let scopes = scopes.into_iter().map(|s| s.as_ref().to_owned()).collect();
+        Self::new_with(self.auth.with_scopes(scopes), self.service.clone())
+    }
+
+    /// Returns a service that authorizes requests with ID tokens for `audience` instead of access
+    /// tokens, e.g. to call a Cloud Run service. Tokens are cached per audience, so services
+    /// derived with the same audience share a token.
+    ///
+    /// The credentials must support ID tokens, i.e. be impersonated or metadata server
+    /// credentials, otherwise `poll_ready` fails with [`AuthError::IdTokenUnsupported`].
+    ///
+    /// [`AuthError::IdTokenUnsupported`]: crate::AuthError::IdTokenUnsupported
+    pub fn with_audience(&self, audience: impl Into<String>) -> Self {
+        Self::new_with(self.auth.with_audience(audience.into()), self.service.clone())
+    }
+
+    /// Returns a service that authorizes each request with an ID token whose audience is the
+    /// scheme and host of the request URI, e.g. `https://my-service-xyz.a.run.app`. This lets a
+    /// gateway call many backends with one service. See [`with_audience`](Self::with_audience).
+    pub fn id_token_per_host(self) -> IdTokenPerHost<S> {
+        IdTokenPerHost { inner: self }
+    }
+
+    /// Returns a service that, when the inner service responds with 401 Unauthorized, invalidates
+    /// the token and retries the request once with a freshly fetched token. The token is
+    /// invalidated only if it is still the one the request was sent with, so concurrent requests
+    /// rejected with the same token refresh it once.
+    ///
+    /// The request is cloned before it is sent, so its body must implement `Clone`. The
+    /// extensions of the request are not carried over to the retried request.
+    ///
+    /// This is a separate service rather than a builder option because the retry needs bounds
+    /// that `GoogleAuthz` does not have, i.e. a `Clone` request body and an HTTP response, and
+    /// `GoogleAuthz` stays usable with any service.
+    pub fn retry_on_unauthorized(self) -> RetryOnUnauthorized<S> {
+        RetryOnUnauthorized { inner: self }
+    }
 }
 
 impl<S: Clone> Clone for GoogleAuthz<S> {
     fn clone(&self) -> Self {
-        Self { auth: self.auth.clone(), service: self.service.clone() }
+        // A clone is not ready until it is polled, like other services.
+        Self::new_with(self.auth.clone(), self.service.clone())
     }
 }
 
@@ -115,14 +559,21 @@ where
     >;
 
     fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match self.auth.poll_ready(cx) {
-            Poll::Ready(Ok(())) => self.service.poll_ready(cx).map_err(Error::Service),
-            Poll::Ready(Err(err)) => Poll::Ready(Err(Error::GoogleAuthz(err))),
-            Poll::Pending => Poll::Pending,
+        // The inner service is polled even while the token is fetched, so that services that
+        // make progress only when polled, e.g. connection pools, are not starved.
+        if !self.auth_ready {
+            let auth = self.auth.poll_ready(cx).map_err(Error::GoogleAuthz)?;
+            self.auth_ready = auth.is_ready();
+        }
+        let service = self.service.poll_ready(cx).map_err(Error::Service)?;
+        match service {
+            Poll::Ready(()) if self.auth_ready => Poll::Ready(Ok(())),
+            _ => Poll::Pending,
         }
     }
 
     fn call(&mut self, req: Request<B>) -> Self::Future {
+        self.auth_ready = false;
         match self.auth.call(req) {
             Ok(req) => Either::Left(self.service.call(req).map_err(Error::Service)),
             Err(err) => Either::Right(future::ready(Err(Error::GoogleAuthz(err)))),
@@ -130,6 +581,112 @@ where
     }
 }
 
+/// A [`GoogleAuthz`] that authorizes each request with an ID token for the host of the request.
+/// See [`GoogleAuthz::id_token_per_host`].
+#[derive(Clone, Debug)]
+pub struct IdTokenPerHost<S> {
+    inner: GoogleAuthz<S>,
+}
+
+impl<S, B> tower_service::Service<Request<B>> for IdTokenPerHost<S>
+where
+    S: tower_service::Service<Request<B>> + Clone + Send + 'static,
+    S::Response: Send,
+    S::Future: Send,
+    S::Error: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Error<S::Error>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    // The audience is known only when a request is given, so the service derived for the
+    // audience is made ready in `call`.
+    fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut authz = match audience(&req) {
+            Some(audience) => self.inner.with_audience(audience),
+            None => {
+                let err = Error::GoogleAuthz(auth::Error::MissingAudience);
+                return Box::pin(future::ready(Err(err)));
+            }
+        };
+        Box::pin(async move {
+            futures_util::future::poll_fn(|cx| authz.poll_ready(cx)).await?;
+            authz.call(req).await
+        })
+    }
+}
+
+// Cloud Run and Cloud Functions expect the URL of the service without a path as the audience.
+// The `Host` header is used if the request URI has no authority.
+fn audience<B>(req: &Request<B>) -> Option<String> {
+    let uri = req.uri();
+    let scheme = uri.scheme_str().unwrap_or("https");
+    match uri.authority() {
+        Some(authority) => Some(format!("{}://{}", scheme, authority)),
+        None => {
+            let host = req.headers().get(HOST)?.to_str().ok()?;
+            Some(format!("{}://{}", scheme, host))
+        }
+    }
+}
+
+/// A [`GoogleAuthz`] that retries requests rejected with 401 Unauthorized once with a fresh token.
+/// See [`GoogleAuthz::retry_on_unauthorized`].
+#[derive(Clone, Debug)]
+pub struct RetryOnUnauthorized<S> {
+    inner: GoogleAuthz<S>,
+}
+
+impl<S, B, ResBody> tower_service::Service<Request<B>> for RetryOnUnauthorized<S>
+where
+    S: tower_service::Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    B: Clone + Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = Error<S::Error>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let retry_req = clone_request(&req);
+        let sent = self.inner.auth.token_value();
+        let fut = self.inner.call(req);
+        let mut retry = self.inner.clone();
+        Box::pin(async move {
+            let resp = fut.await?;
+            if resp.status() != StatusCode::UNAUTHORIZED {
+                return Ok(resp);
+            }
+            warn!("request is unauthorized, retry with a new token");
+            if let Some(ref sent) = sent {
+                retry.auth.invalidate_token_value(sent);
+            }
+            futures_util::future::poll_fn(|cx| retry.poll_ready(cx)).await?;
+            retry.call(retry_req).await
+        })
+    }
+}
+
+fn clone_request<B: Clone>(req: &Request<B>) -> Request<B> {
+    let mut clone = Request::new(req.body().clone());
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.version_mut() = req.version();
+    *clone.headers_mut() = req.headers().clone();
+    clone
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -163,4 +720,426 @@ mod test {
         assert_send(&svc);
         assert_sync(&svc);
     }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as _;
+
+        let err: Error<std::io::Error> = Error::Service(std::io::ErrorKind::TimedOut.into());
+        let source = err.source().unwrap().downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), std::io::ErrorKind::TimedOut);
+        // Chain printers, e.g. `anyhow`, show the source after the message.
+        assert!(!err.to_string().contains(&source.to_string()));
+
+        let err: Error<std::io::Error> = auth::Error::Timeout.into();
+        let source = err.source().unwrap().downcast_ref::<auth::Error>().unwrap();
+        assert!(matches!(source, auth::Error::Timeout));
+        assert!(!err.to_string().contains(&source.to_string()));
+    }
+
+    #[test]
+    fn test_extra_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-goog-request-reason", HeaderValue::from_static("debugging"));
+        let builder = GoogleAuthz::builder(()).extra_headers(headers.clone()).unwrap();
+        assert_eq!(builder.config.extra_headers.len(), 1);
+        assert_eq!(builder.config.extra_headers["x-goog-request-reason"], "debugging");
+
+        for (name, value) in [(AUTHORIZATION.as_str(), "Bearer other"), ("x-goog-api-key", "other")]
+        {
+            let mut headers = headers.clone();
+            headers.insert(name, HeaderValue::from_static(value));
+            let res = GoogleAuthz::builder(()).extra_headers(headers);
+            assert!(matches!(res, Err(auth::Error::ReservedHeader(n)) if n == name));
+        }
+    }
+
+    #[test]
+    fn test_jwt_lifetime() {
+        let builder = GoogleAuthz::builder(()).jwt_lifetime(Duration::from_secs(600));
+        assert_eq!(builder.config.jwt_lifetime, Duration::from_secs(600));
+        let builder = GoogleAuthz::builder(()).jwt_lifetime(Duration::from_secs(7200));
+        assert_eq!(builder.config.jwt_lifetime, MAX_JWT_LIFETIME);
+    }
+
+    #[test]
+    fn test_extra_jwt_claims() {
+        let mut claims = serde_json::Map::new();
+        claims.insert("custom".into(), 1.into());
+        let builder = GoogleAuthz::builder(()).extra_jwt_claims(claims.clone()).unwrap();
+        assert_eq!(builder.config.extra_jwt_claims, claims);
+        claims.insert("exp".into(), 1.into());
+        let res = GoogleAuthz::builder(()).extra_jwt_claims(claims);
+        assert!(matches!(res, Err(auth::Error::ReservedClaim(c)) if c == "exp"));
+    }
+
+    #[test]
+    fn test_refresh_threshold() {
+        let builder = GoogleAuthz::builder(()).refresh_threshold(RefreshThreshold::Fraction(0.75));
+        assert_eq!(builder.config.refresh_threshold, RefreshThreshold::Fraction(0.75));
+        let builder = GoogleAuthz::builder(()).refresh_threshold(RefreshThreshold::Fraction(1.5));
+        assert_eq!(builder.config.refresh_threshold, RefreshThreshold::Fraction(1.0));
+        let builder = GoogleAuthz::builder(()).refresh_threshold(RefreshThreshold::Fraction(-1.0));
+        assert_eq!(builder.config.refresh_threshold, RefreshThreshold::Fraction(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_auth_ready() {
+        use tower_service::Service as _;
+
+        // Ready on every other poll.
+        #[derive(Clone)]
+        struct Flip(bool);
+
+        impl tower_service::Service<Request<()>> for Flip {
+            type Response = ();
+            type Error = ();
+            type Future = Ready<Result<(), ()>>;
+
+            fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.0 = !self.0;
+                if self.0 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(Ok(()))
+                }
+            }
+
+            fn call(&mut self, _: Request<()>) -> Self::Future {
+                future::ready(Ok(()))
+            }
+        }
+
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut svc = GoogleAuthz::builder(Flip(false)).credentials(credentials).build().await;
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(svc.poll_ready(&mut cx).is_pending());
+        assert!(svc.auth_ready);
+        assert!(!svc.clone().auth_ready);
+        assert!(matches!(svc.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        svc.call(req).await.unwrap();
+        assert!(!svc.auth_ready);
+    }
+
+    #[test]
+    fn test_with_credentials() {
+        // No runtime is needed.
+        let svc = GoogleAuthz::with_credentials((), Credentials::ApiKey("api-key".into()));
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        let req = svc.auth.call(req).unwrap();
+        assert_eq!(req.uri(), "https://example.com/?key=api-key");
+    }
+
+    #[tokio::test]
+    async fn test_set_credentials() {
+        async fn mock(token: &str) -> Credentials {
+            let fetcher = crate::MockFetcher::new(token, Duration::from_secs(3600));
+            Credentials::builder().fetcher(fetcher).build().await.unwrap()
+        }
+
+        let mut svc = GoogleAuthz::with_credentials((), mock("a").await);
+        let mut scoped = svc.with_scopes(["scope"]);
+        let mut clone = svc.clone();
+        assert_eq!(svc.token().await.unwrap().unwrap(), "Bearer a");
+
+        svc.set_credentials(mock("b").await);
+        assert_eq!(svc.token().await.unwrap().unwrap(), "Bearer b");
+        assert_eq!(clone.token().await.unwrap().unwrap(), "Bearer b");
+        assert_eq!(scoped.token().await.unwrap().unwrap(), "Bearer b");
+
+        clone.set_credentials(Credentials::None);
+        assert!(!svc.is_token_valid());
+        assert_eq!(svc.token().await.unwrap(), None);
+        assert!(svc.is_token_valid());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut svc = GoogleAuthz::with_credentials((), credentials);
+        assert!(svc.token_ttl().is_none());
+        svc.prefetch().await.unwrap();
+        assert!(svc.token_ttl().is_some());
+
+        // An empty token is rejected.
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut svc = GoogleAuthz::builder(()).max_retry(0).build_with_credentials(credentials);
+        assert!(matches!(svc.prefetch().await, Err(auth::Error::TokenFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn test_authorization_header() {
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut svc = GoogleAuthz::with_credentials((), credentials);
+        assert_eq!(svc.authorization_header().await.unwrap(), "Bearer token");
+
+        let mut svc = GoogleAuthz::with_credentials((), Credentials::ApiKey("api-key".into()));
+        assert!(matches!(svc.authorization_header().await, Err(auth::Error::TokenUnsupported)));
+    }
+
+    #[tokio::test]
+    async fn test_quota_project() {
+        let credentials = Credentials::builder().no_credentials().build().await.unwrap();
+        let mut authorizer = GoogleAuthz::builder(())
+            .quota_project("billing-project")
+            .build_with_credentials(credentials)
+            .authorizer();
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        let req = authorizer.authorize(req).await.unwrap();
+        assert_eq!(req.headers()["x-goog-user-project"], "billing-project");
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_unauthorized() {
+        use tower_service::Service as _;
+
+        #[derive(Clone)]
+        struct Unauthorized(Arc<std::sync::Mutex<Vec<HeaderValue>>>);
+
+        impl tower_service::Service<Request<()>> for Unauthorized {
+            type Response = Response<()>;
+            type Error = ();
+            type Future = Ready<Result<Response<()>, ()>>;
+
+            fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: Request<()>) -> Self::Future {
+                let mut seen = self.0.lock().unwrap();
+                seen.push(req.headers()[hyper::header::AUTHORIZATION].clone());
+                let status = if seen.len() == 1 { 401 } else { 200 };
+                future::ready(Ok(Response::builder().status(status).body(()).unwrap()))
+            }
+        }
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let mut svc = GoogleAuthz::builder(Unauthorized(seen.clone()))
+            .credentials(credentials)
+            .build()
+            .await
+            .retry_on_unauthorized();
+
+        futures_util::future::poll_fn(|cx| svc.poll_ready(cx)).await.unwrap();
+        let resp = svc.call(Request::get("https://example.com/").body(()).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_unauthorized_concurrent() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tower_service::Service as _;
+
+        #[derive(Debug)]
+        struct Counting(Arc<AtomicUsize>);
+
+        impl crate::Fetcher for Counting {
+            fn fetch(&self, _: Option<&[String]>) -> crate::ResponseFuture {
+                let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+                Box::pin(future::ready(Ok(crate::TokenResponse {
+                    token_type: "Bearer".into(),
+                    access_token: format!("token-{}", n),
+                    expires_in: 3600,
+                    scope: None,
+                })))
+            }
+        }
+
+        // Rejects the first token.
+        #[derive(Clone)]
+        struct RejectFirst;
+
+        impl tower_service::Service<Request<()>> for RejectFirst {
+            type Response = Response<()>;
+            type Error = ();
+            type Future = Ready<Result<Response<()>, ()>>;
+
+            fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: Request<()>) -> Self::Future {
+                let status =
+                    if req.headers()[AUTHORIZATION] == "Bearer token-1" { 401 } else { 200 };
+                future::ready(Ok(Response::builder().status(status).body(()).unwrap()))
+            }
+        }
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let credentials =
+            Credentials::builder().fetcher(Counting(fetches.clone())).build().await.unwrap();
+        let mut svc1 = GoogleAuthz::builder(RejectFirst)
+            .credentials(credentials)
+            .build()
+            .await
+            .retry_on_unauthorized();
+        let mut svc2 = svc1.clone();
+        futures_util::future::poll_fn(|cx| svc1.poll_ready(cx)).await.unwrap();
+        futures_util::future::poll_fn(|cx| svc2.poll_ready(cx)).await.unwrap();
+        let req = || Request::get("https://example.com/").body(()).unwrap();
+        let (resp1, resp2) = futures_util::future::join(svc1.call(req()), svc2.call(req())).await;
+        assert_eq!(resp1.unwrap().status(), StatusCode::OK);
+        assert_eq!(resp2.unwrap().status(), StatusCode::OK);
+        // The first fetch and a single refetch.
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_id_token_per_host() {
+        use tower_service::Service as _;
+
+        #[derive(Debug)]
+        struct Audience;
+
+        impl crate::Fetcher for Audience {
+            fn fetch(&self, _: Option<&[String]>) -> crate::ResponseFuture {
+                unreachable!("access token is not used")
+            }
+
+            fn fetch_id_token(&self, audience: &str) -> crate::ResponseFuture {
+                Box::pin(future::ready(Ok(crate::TokenResponse {
+                    token_type: "Bearer".into(),
+                    access_token: audience.replace("://", "-"),
+                    expires_in: 3600,
+                    scope: None,
+                })))
+            }
+        }
+
+        #[derive(Clone)]
+        struct Echo;
+
+        impl tower_service::Service<Request<()>> for Echo {
+            type Response = Request<()>;
+            type Error = ();
+            type Future = Ready<Result<Request<()>, ()>>;
+
+            fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: Request<()>) -> Self::Future {
+                future::ready(Ok(req))
+            }
+        }
+
+        let credentials = Credentials::builder().fetcher(Audience).build().await.unwrap();
+        let mut svc =
+            GoogleAuthz::builder(Echo).credentials(credentials).build().await.id_token_per_host();
+
+        let req = Request::get("https://a.run.app/path").body(()).unwrap();
+        let req = svc.call(req).await.unwrap();
+        assert_eq!(req.headers()[hyper::header::AUTHORIZATION], "Bearer https-a.run.app");
+
+        let req = Request::get("/path").header(HOST, "b.run.app").body(()).unwrap();
+        assert_eq!(audience(&req).as_deref(), Some("https://b.run.app"));
+        let req = Request::get("/path").body(()).unwrap();
+        assert!(matches!(
+            svc.call(req).await,
+            Err(Error::GoogleAuthz(auth::Error::MissingAudience))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_enforce_https() {
+        let mut authz = GoogleAuthz::builder(())
+            .credentials(Credentials::None)
+            .enforce_https(true)
+            .build_authorizer()
+            .await;
+        let req = Request::get("http://example.com/").body(()).unwrap();
+        assert!(matches!(authz.authorize(req).await, Err(auth::Error::EnforceHttps(Some(_)))));
+        let req = Request::get("https://example.com/").body(()).unwrap();
+        assert!(authz.authorize(req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_from_auth() {
+        let credentials = Credentials::builder()
+            .fetcher(crate::MockFetcher::new("token", Duration::from_secs(3600)))
+            .build()
+            .await
+            .unwrap();
+        let auth = GoogleAuthz::builder(()).credentials(credentials).build_authorizer().await;
+        let mut a = GoogleAuthz::from_auth(auth.clone(), ());
+        let b = GoogleAuthz::from_auth(auth, ());
+        assert_eq!(b.token_ttl(), None);
+        a.token().await.unwrap();
+        assert!(b.token_ttl().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_poll_ready() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tower_service::Service as _;
+
+        #[derive(Debug)]
+        struct Pending;
+
+        impl crate::Fetcher for Pending {
+            fn fetch(&self, _: Option<&[String]>) -> crate::ResponseFuture {
+                Box::pin(futures_util::future::pending())
+            }
+        }
+
+        struct Polled(Arc<AtomicUsize>);
+
+        impl tower_service::Service<Request<()>> for Polled {
+            type Response = ();
+            type Error = ();
+            type Future = Ready<Result<(), ()>>;
+
+            fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _: Request<()>) -> Self::Future {
+                future::ready(Ok(()))
+            }
+        }
+
+        let polled = Arc::new(AtomicUsize::new(0));
+        let credentials = Credentials::builder().fetcher(Pending).build().await.unwrap();
+        let mut svc =
+            GoogleAuthz::builder(Polled(polled.clone())).credentials(credentials).build().await;
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        assert!(svc.poll_ready(&mut cx).is_pending());
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token() {
+        let credentials = Credentials::builder().no_credentials().build().await.unwrap();
+        let mut authz = GoogleAuthz::builder(()).credentials(credentials).build().await;
+        assert_eq!(authz.token().await.unwrap(), None);
+    }
 }